@@ -1,7 +1,57 @@
+use crate::models::db::{ColumnType, TableColumns};
 use sqlx::postgres::PgRow;
 use sqlx::Error as SqlxError;
 use std::collections::HashMap;
 
+// the column soft delete tables mark their deleted rows with
+pub const SOFT_DELETE_COLUMN: &str = "deleted_at";
+
+// a column of a TableModel as it exists in the database
+#[derive(Debug, Clone)]
+pub struct ColumnDef {
+    pub name: &'static str,
+    pub data_type: &'static str, // postgres type, e.g. "bigint" or "text"
+    pub nullable: bool,
+    pub primary_key: bool,
+    pub sensitive: bool,
+}
+
+impl ColumnDef {
+    pub const fn new(name: &'static str, data_type: &'static str) -> Self {
+        Self {
+            name,
+            data_type,
+            nullable: false,
+            primary_key: false,
+            sensitive: false,
+        }
+    }
+
+    pub const fn nullable(mut self) -> Self {
+        self.nullable = true;
+        self
+    }
+
+    // only used when generating the CREATE TABLE statement of the model
+    pub const fn primary_key(mut self) -> Self {
+        self.primary_key = true;
+        self
+    }
+
+    // left out of reads unless the column is asked for by name, e.g. password hashes
+    pub const fn sensitive(mut self) -> Self {
+        self.sensitive = true;
+        self
+    }
+
+    pub fn column_type(&self) -> ColumnType {
+        ColumnType {
+            data_type: self.data_type.to_string(),
+            nullable: self.nullable,
+        }
+    }
+}
+
 pub trait TableModel: Send + Sync {
     fn from_row(row: &PgRow) -> Result<Self, SqlxError>
     where
@@ -9,17 +59,55 @@ pub trait TableModel: Send + Sync {
     fn table_name() -> &'static str
     where
         Self: Sized;
+    // models that do not declare their columns cannot be joined, migrated
+    // or used to look up the column types of their table
+    fn columns() -> Vec<ColumnDef>
+    where
+        Self: Sized,
+    {
+        Vec::new()
+    }
+    // soft delete tables need a nullable SOFT_DELETE_COLUMN (timestamptz),
+    // deleting a row only sets it instead of removing the row
+    fn soft_delete() -> bool
+    where
+        Self: Sized,
+    {
+        false
+    }
+    // owner-scoped tables hold rows of several users in this column,
+    // requests with an owner only ever see and touch the rows of that owner
+    fn owner_column() -> Option<&'static str>
+    where
+        Self: Sized,
+    {
+        None
+    }
     fn debug_string(&self) -> String;
     fn as_value(&self) -> serde_json::Value;
     fn as_hash_map(&self) -> HashMap<String, serde_json::Value>;
     fn get_keys_as_hashmap(&self, keys: Vec<&str>) -> HashMap<String, serde_json::Value>;
 }
 
-pub type ModelFactory = fn(&PgRow) -> Box<dyn TableModel + Send + Sync>;
+pub type ModelFactory = fn(&PgRow) -> Result<Box<dyn TableModel + Send + Sync>, SqlxError>;
 
 #[derive(Debug)]
 pub struct ModelEntry {
     pub factory: ModelFactory,
+    pub columns: Vec<ColumnDef>,
+    pub soft_delete: bool,
+    pub owner_column: Option<&'static str>,
+}
+
+impl ModelEntry {
+    // the columns a read selects if it does not name any
+    pub fn default_columns(&self) -> impl Iterator<Item = &ColumnDef> {
+        self.columns.iter().filter(|column| !column.sensitive)
+    }
+
+    pub fn has_sensitive_columns(&self) -> bool {
+        self.columns.iter().any(|column| column.sensitive)
+    }
 }
 
 #[derive(Debug)]
@@ -39,7 +127,10 @@ impl ModelRegistry {
         self.models.insert(
             table_name,
             ModelEntry {
-                factory: |row| Box::new(T::from_row(row).unwrap()),
+                factory: |row| Ok(Box::new(T::from_row(row)?)),
+                columns: T::columns(),
+                soft_delete: T::soft_delete(),
+                owner_column: T::owner_column(),
             },
         );
     }
@@ -47,4 +138,18 @@ impl ModelRegistry {
     pub fn get(&self, table_name: &str) -> Option<&ModelEntry> {
         self.models.get(table_name)
     }
+
+    // the columns of a registered table in the format the QueryBuilder expects
+    // None if the model declares no columns, since then its column types are unknown
+    pub fn table_columns(&self, table_name: &str) -> Option<TableColumns> {
+        self.get(table_name)
+            .filter(|entry| !entry.columns.is_empty())
+            .map(|entry| {
+                entry
+                    .columns
+                    .iter()
+                    .map(|column| (column.name.to_string(), column.column_type()))
+                    .collect()
+            })
+    }
 }
@@ -4,7 +4,7 @@ use sqlx::postgres::PgRow;
 use sqlx::Row;
 use std::collections::HashMap;
 
-use crate::db::TableModel;
+use crate::db::{ColumnDef, TableModel, SOFT_DELETE_COLUMN};
 use crate::to_string_;
 
 use acid4sigmas_attr::TableName;
@@ -33,6 +33,24 @@ impl TableModel for User {
         let table = User::table_name_();
         table
     }
+    fn columns() -> Vec<ColumnDef> {
+        vec![
+            ColumnDef::new("uid", "bigint").primary_key(),
+            ColumnDef::new("email", "text"),
+            ColumnDef::new("owner", "boolean"),
+            ColumnDef::new("email_verified", "boolean"),
+            ColumnDef::new("username", "text"),
+            ColumnDef::new(SOFT_DELETE_COLUMN, "timestamptz").nullable(),
+        ]
+    }
+    // deleted accounts can be restored for a while before they get purged
+    fn soft_delete() -> bool {
+        true
+    }
+    // every user only ever owns their own row
+    fn owner_column() -> Option<&'static str> {
+        Some("uid")
+    }
     fn debug_string(&self) -> String {
         format!(
             "User {{ uid: {}, email: {}, owner: {}, email_verified: {}, username: {} }}",
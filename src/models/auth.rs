@@ -1,5 +1,8 @@
 use crate::to_string_;
-use crate::{db::TableModel, utils::deserializer::custom_deserialize};
+use crate::{
+    db::{ColumnDef, TableModel},
+    utils::deserializer::custom_deserialize,
+};
 use acid4sigmas_attr::TableName;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
@@ -58,6 +61,18 @@ impl TableModel for AuthUser {
         let table = AuthUser::table_name_();
         table
     }
+    fn columns() -> Vec<ColumnDef> {
+        vec![
+            ColumnDef::new("uid", "bigint").primary_key(),
+            ColumnDef::new("email", "text"),
+            ColumnDef::new("email_verified", "boolean"),
+            ColumnDef::new("username", "text"),
+            ColumnDef::new("password_hash", "text").sensitive(),
+        ]
+    }
+    fn owner_column() -> Option<&'static str> {
+        Some("uid")
+    }
     fn debug_string(&self) -> String {
         format!(
             "AuthUser {{ uid: {}, email: {}, email_verified: {}, username: {}, password_hash: {} }}",
@@ -116,6 +131,17 @@ impl TableModel for AuthTokens {
         let table = AuthTokens::table_name_();
         table
     }
+    fn columns() -> Vec<ColumnDef> {
+        vec![
+            ColumnDef::new("jti", "text").primary_key(),
+            ColumnDef::new("uid", "bigint"),
+            ColumnDef::new("expires_at", "bigint"),
+        ]
+    }
+    // a user has one row per token they were given
+    fn owner_column() -> Option<&'static str> {
+        Some("uid")
+    }
     fn debug_string(&self) -> String {
         format!(
             "AuthToken {{ uid: {}, jti: {}, expires_at: {} }}",
@@ -1,12 +1,89 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
+use crate::db::{ModelRegistry, TableModel};
+use crate::utils::deserializer::one_or_many;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use sqlx::PgPool;
+use tokio::sync::RwLock;
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum DeleteAction {
     DeleteTable,
-    DeleteValue,
+    DeleteValue, // only marks the rows as deleted on soft delete tables
+    Purge,       // really deletes the rows, even on soft delete tables
+}
+
+// what an upsert does when the row already exists
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum ConflictAction {
+    DoNothing,
+    DoUpdate(Vec<String>), // the columns that get overwritten with the new values
+}
+
+// INSERT ... ON CONFLICT (target) DO ...
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct OnConflict {
+    pub target: Vec<String>,
+    pub action: ConflictAction,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum AggregateFunction {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+// e.g. {"function": "Count"} for COUNT(*)
+// or {"function": "Sum", "column": "amount", "alias": "total"}
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AggregateExpr {
+    pub function: AggregateFunction,
+    pub column: Option<String>,
+    pub alias: Option<String>, // defaults to e.g. "count" or "sum_amount"
+}
+
+// a HAVING condition, compares the result of an aggregate
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct HavingCondition {
+    pub aggregate: AggregateExpr,
+    pub op: Comparison,
+}
+
+// SELECT group_by.., aggregates.. FROM table GROUP BY group_by.. HAVING having..
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Aggregate {
+    pub aggregates: Vec<AggregateExpr>,
+    #[serde(default)]
+    pub group_by: Vec<String>,
+    #[serde(default)]
+    pub having: Vec<HavingCondition>, // all conditions have to match
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum JoinKind {
+    Inner,
+    Left,
+}
+
+// left is a column of the requested table or a qualified column ("table.column")
+// of a table joined before, right is a column of the joined table
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct JoinOn {
+    pub left: String,
+    pub right: String,
+}
+
+// e.g. {"kind": "Inner", "table": "auth_tokens", "on": [{"left": "uid", "right": "uid"}]}
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Join {
+    pub kind: JoinKind,
+    pub table: String,
+    pub on: Vec<JoinOn>,
 }
 
 // the actions we perform for the database
@@ -17,57 +94,256 @@ pub enum DatabaseAction {
     Delete(DeleteAction),
     Update,
     Retrieve,
+    Upsert(OnConflict),
+    Aggregate(Aggregate),
+    Transaction(Vec<DatabaseRequest>), // runs all requests in order, rolls back if one fails
 }
 
 // the ordering direction
-#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
 pub enum OrderDirection {
     Asc,
     Desc,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+// where the nulls of a sort key go
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum NullsOrder {
+    First,
+    Last,
+}
+
+// a single sort key
+// e.g. {"column": "created_at", "direction": "Desc", "nulls": "Last"}
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct OrderBy {
     pub column: String,
     pub direction: OrderDirection,
+    pub nulls: Option<NullsOrder>, // postgres default if not set, last for Asc and first for Desc
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+// the comparison a condition applies to its column
+// e.g. {"Lt": 1700000000}, {"In": [1, 2, 3]}, {"Between": [1, 10]} or "IsNull"
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum Comparison {
+    Eq(Value),
+    NotEq(Value),
+    Lt(Value),
+    Lte(Value),
+    Gt(Value),
+    Gte(Value),
+    Like(String),
+    ILike(String),
+    In(Vec<Value>),
+    IsNull,
+    IsNotNull,
+    Between(Value, Value),
+    Contains(Value), // jsonb only, the column contains the given json (@>)
+    HasKey(String),  // jsonb only, the object has the given top level key (?)
+}
+
+// a single column condition
+// e.g. {"column": "expires_at", "op": {"Lt": 1700000000}}
+// with a path the condition applies to the value inside of a json column
+// e.g. {"column": "preferences", "path": ["theme", "color"], "op": {"Eq": "dark"}}
+// that value is compared as text, so Lt, Lte, Gt, Gte and Between are refused on a path
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Condition {
+    pub column: String,
+    pub path: Option<Vec<String>>,
+    pub op: Comparison,
+}
+
+// sets the value at a path inside of a jsonb column on update, the rest of the document stays
+// only the last key of the path gets created if it is missing
+// e.g. {"column": "preferences", "path": ["theme", "color"], "value": "dark"}
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct JsonUpdate {
+    pub column: String,
+    pub path: Vec<String>,
+    pub value: Value,
+}
+
+// the search conditions, And / Or / Single are the flat equality forms
+// All / Any / Not can be nested to build up a condition tree
+// e.g. (a = 1 OR b = 2) AND c = 3:
+// {"All": [{"Or": {"a": 1, "b": 2}}, {"column": "c", "op": {"Eq": 3}}]}
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum WhereClause {
     And(HashMap<String, Value>),
     Or(HashMap<String, Value>),
     Single(HashMap<String, Value>),
+    All(Vec<WhereNode>), // every node has to match
+    Any(Vec<WhereNode>), // at least one node has to match
+    Not(Box<WhereNode>), // the node must not match
+}
+
+// a single entry of the condition tree
+// either a plain condition or another (nested) where clause
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum WhereNode {
+    Condition(Condition),
+    Clause(WhereClause),
+}
+// full-text search over one or more columns
+// matches rows where plainto_tsquery(query) matches the columns joined by spaces
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Search {
+    pub columns: Vec<String>,
+    pub query: String,
+    pub language: Option<String>, // a postgres text search config, "simple" if not set
+    #[serde(default)]
+    pub rank: bool, // order by relevance first, before order_by
 }
+
 // the filters aka the search conditions
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Filters {
     #[serde(rename = "where")]
     pub where_clause: Option<WhereClause>, // use in the request actually "where" instead of "where_clause"
-    pub order_by: Option<OrderBy>,
+    pub search: Option<Search>,
+    // the sort keys in order, a single object is accepted as well
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub order_by: Option<Vec<OrderBy>>,
     pub limit: Option<u32>,
     pub offset: Option<u32>,
+    // the next_cursor of the previous page, continues right after its last row
+    // the order_by has to be the same as for the previous page and should end with a unique column
+    pub cursor: Option<String>,
+}
+
+// what an opaque cursor holds, the sort keys and the values of the last row for them
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Cursor {
+    pub order_by: Vec<OrderBy>,
+    pub values: Vec<Value>,
 }
 
 // the request struct itself
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct DatabaseRequest {
+    #[serde(default)] // a Transaction has no table itself
     pub table: String,
     pub action: DatabaseAction,
     pub values: Option<HashMap<String, Value>>,
     pub bulk_values: Option<Vec<HashMap<String, Value>>>,
     pub filters: Option<Filters>,
+    // nested values an Update sets inside of jsonb columns, next to or instead of values
+    pub json_updates: Option<Vec<JsonUpdate>>,
+    // the columns a Retrieve selects, all columns (*) if not specified
+    // for registered tables that means every column that is not sensitive,
+    // so auth_users.password_hash only comes back if it is named here
+    pub columns: Option<Vec<String>>,
+    // the columns Insert, BulkInsert, Update and Delete give back (RETURNING)
+    // the returned rows come back as DatabaseResponse::Data
+    pub returning: Option<Vec<String>>,
+    // tables joined into a Retrieve, the rows come back with table prefixed keys ("users.uid")
+    // columns then have to be qualified as well
+    pub joins: Option<Vec<Join>>,
+    // DROP TABLE and deleting without a where clause (TRUNCATE) are refused without this
+    #[serde(default)]
+    pub confirm_destructive: bool,
+    // a bulk insert too large for one statement gets split up,
+    // this runs all of those statements in one transaction
+    #[serde(default)]
+    pub atomic: bool,
+    // rows of soft delete tables that are marked as deleted are skipped unless this is set
+    #[serde(default)]
+    pub include_deleted: bool,
+    // gives back the generated sql and bind parameters instead of running them
+    #[serde(default)]
+    pub dry_run: bool,
+    // like dry_run, but also gives back the plan of EXPLAIN (FORMAT JSON), nothing gets executed
+    #[serde(default)]
+    pub explain: bool,
+    // the uid the request runs on behalf of, owner-scoped tables only give that user's rows
+    // and get it forced into inserted rows
+    // never (de)serialized, the QueryExecutor sets it from the owner it is called with
+    #[serde(skip)]
+    pub owner: Option<Value>,
+    // echoed back in the DatabaseReply so the client can match the reply to the request
+    #[serde(default)]
+    pub request_id: Option<String>,
+    // reads also count every row they match, without limit, offset and cursor (PageInfo::total)
+    // this runs a second query, so only ask for it if it is shown
+    #[serde(default)]
+    pub count_total: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum DatabaseResponse<T> {
-    Error { error: String },
-    Status { status: String },
+    Error {
+        error: String,
+        #[serde(default)] // responses from before the codes existed count as Internal
+        code: DatabaseErrorCode,
+    },
+    Status {
+        status: String,
+        #[serde(default)]
+        rows_affected: Option<u64>, // set for writes without returning
+    },
     Data(Vec<T>),
+    Page {
+        data: Vec<T>,
+        page: PageInfo,
+    }, // the rows of a read that was limited
+}
+
+// what went wrong, stable so handlers can answer with the right status (see status_code)
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DatabaseErrorCode {
+    UniqueViolation,     // 23505, e.g. a username that is taken already
+    ForeignKeyViolation, // 23503
+    NotFound,            // an owner-scoped update or delete matched no rows
+    Validation,          // the request was refused before it reached the database
+    PermissionDenied,    // refused by the QueryPolicy or by postgres (42501)
+    TypeConversion,      // a value does not fit the type of its column (22P02, ...)
+    #[default]
+    Internal,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DatabaseError {
+    pub code: DatabaseErrorCode,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PageInfo {
+    pub limit: u32,
+    pub offset: u32,
+    pub has_more: bool, // there are rows after this page
+    #[serde(default)]
+    pub next_cursor: Option<String>, // set if there are more rows and the read was ordered
+    #[serde(default)]
+    pub cursor: Option<String>, // the cursor this page continued from
+    #[serde(default)]
+    pub total: Option<u64>, // only counted if the request asked for count_total
+}
+
+// a response together with the id of the request it answers
+// without a request_id it serializes exactly like the plain response
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DatabaseReply<T> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(flatten)]
+    pub response: DatabaseResponse<T>,
 }
 
 pub type Values = HashMap<String, Value>;
 pub type BulkValues = Vec<HashMap<String, Value>>;
-pub type TableColumns = HashMap<String, String>;
+// the postgres type of a column, e.g. "bigint", "varchar(255)", "timestamptz" or "text[]"
+// and whether it accepts null
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ColumnType {
+    pub data_type: String,
+    #[serde(default)]
+    pub nullable: bool,
+}
+
+pub type TableColumns = HashMap<String, ColumnType>;
 
 pub struct QueryBuilder {
     pub table: String,
@@ -75,12 +351,96 @@ pub struct QueryBuilder {
     pub filters: Option<Filters>,
     pub bulk_values: Option<BulkValues>,
     pub values: Option<Values>,
+    pub json_updates: Option<Vec<JsonUpdate>>,
     pub table_columns: Option<TableColumns>,
+    pub columns: Option<Vec<String>>,
+    pub returning: Option<Vec<String>>,
+    pub joins: Option<Vec<Join>>,
+    pub model_registry: Option<Arc<ModelRegistry>>,
+    pub allowed_tables: Option<HashSet<String>>, // tables that may be queried next to the registered ones
+    pub confirm_destructive: bool,
+    pub include_deleted: bool,
+    pub owner: Option<Value>,
+    pub limits: Option<TableLimits>, // without them the limit of the filters is used as it is
+    pub fetch_has_more: bool,        // fetch one row more than the limit to tell if there are more
     pub bind_params: Vec<Value>,
 }
 
 pub type BuildQuery = (String, Vec<Value>);
 
+// what a request does to a table, the unit the QueryPolicy allows or denies
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PolicyAction {
+    Retrieve,
+    Aggregate,
+    Insert,
+    BulkInsert,
+    Upsert,
+    Update,
+    DeleteValue,
+    Purge,    // removes rows for good, even from soft delete tables
+    Truncate, // DeleteValue or Purge that empties the table (TRUNCATE)
+    DropTable,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TablePolicy {
+    pub allowed: HashSet<PolicyAction>,
+}
+
+// decides which actions may run on which table
+// tables without their own policy fall back to the default one
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct QueryPolicy {
+    pub default: TablePolicy,
+    #[serde(default)]
+    pub tables: HashMap<String, TablePolicy>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct TableLimits {
+    pub default_limit: u32, // used if the request has no limit
+    pub max_limit: u32,     // requests with a higher limit get rejected
+}
+
+// how many rows a read may give back
+// tables without their own limits fall back to the default ones
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct QueryLimits {
+    pub default: TableLimits,
+    #[serde(default)]
+    pub tables: HashMap<String, TableLimits>,
+}
+
+// runs DatabaseRequests against postgres
+pub struct QueryExecutor {
+    pub pool: PgPool,
+    pub model_registry: Arc<ModelRegistry>,
+    pub schema_cache: Option<Arc<SchemaCache>>,
+    pub policy: QueryPolicy,
+    pub limits: QueryLimits,
+    pub allowed_tables: HashSet<String>, // queryable next to the registered tables
+}
+
+// the columns of every table as information_schema reports them
+// loaded once and kept until refresh() is called
+pub struct SchemaCache {
+    pub pool: PgPool,
+    pub tables: RwLock<HashMap<String, TableColumns>>,
+}
+
+// what running a request gave back
+pub enum QueryOutput {
+    Models(Vec<Box<dyn TableModel + Send + Sync>>), // rows of a registered table
+    Rows(Vec<Value>), // rows of projections, joins, aggregates and RETURNING as json objects
+    RowsAffected(u64),
+    Transaction(Vec<QueryOutput>), // the output of every request of the transaction
+    Page {
+        output: Box<QueryOutput>, // the Models or Rows of the page
+        page: PageInfo,
+    },
+}
+
 impl Default for QueryBuilder {
     fn default() -> Self {
         Self {
@@ -89,7 +449,18 @@ impl Default for QueryBuilder {
             filters: None,
             bulk_values: None,
             values: None,
+            json_updates: None,
             table_columns: None,
+            columns: None,
+            returning: None,
+            joins: None,
+            model_registry: None,
+            allowed_tables: None,
+            confirm_destructive: false,
+            include_deleted: false,
+            owner: None,
+            limits: None,
+            fetch_has_more: false,
             bind_params: vec![],
         }
     }
@@ -108,9 +479,11 @@ impl Default for Filters {
     fn default() -> Self {
         Filters {
             where_clause: None,
+            search: None,
             order_by: None,
             limit: None,
             offset: None,
+            cursor: None,
         }
     }
 }
@@ -123,6 +496,18 @@ impl Default for DatabaseRequest {
             bulk_values: None,
             values: None,
             filters: None,
+            json_updates: None,
+            columns: None,
+            returning: None,
+            joins: None,
+            confirm_destructive: false,
+            atomic: false,
+            include_deleted: false,
+            dry_run: false,
+            explain: false,
+            owner: None,
+            request_id: None,
+            count_total: false,
         }
     }
 }
@@ -0,0 +1,33 @@
+use anyhow::Result;
+use futures_util::future::BoxFuture;
+use sqlx::PgConnection;
+
+// a migration written in rust, it runs inside the transaction of the migration
+pub type MigrationFn = for<'c> fn(&'c mut PgConnection) -> BoxFuture<'c, Result<()>>;
+
+#[derive(Clone)]
+pub enum MigrationStep {
+    Sql(String),
+    Rust(MigrationFn),
+}
+
+#[derive(Clone)]
+pub struct Migration {
+    pub version: i64, // migrations run ordered by their version, which has to be unique
+    pub name: String,
+    pub up: MigrationStep,
+    pub down: Option<MigrationStep>, // without it the migration cannot be rolled back
+}
+
+// a migration as it is recorded in the tracking table
+#[derive(Debug, Clone)]
+pub struct AppliedMigration {
+    pub version: i64,
+    pub name: String,
+    pub checksum: String,
+}
+
+pub struct MigrationRunner {
+    pub migrations: Vec<Migration>,
+    pub table: String, // the tracking table, "_migrations" by default
+}
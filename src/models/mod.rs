@@ -2,5 +2,6 @@ pub mod api;
 pub mod auth;
 pub mod db;
 pub mod email_client;
+pub mod migrations;
 pub mod redis;
 pub mod totp;
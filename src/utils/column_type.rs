@@ -0,0 +1,639 @@
+use crate::models::db::{ColumnType, DatabaseError};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, NaiveDate};
+use serde_json::Value;
+
+// the postgres types the QueryBuilder knows how to convert values for
+#[derive(Debug, Clone, PartialEq)]
+enum PgType {
+    SmallInt,
+    Integer,
+    BigInt,
+    Real,
+    DoublePrecision,
+    Numeric,
+    Text,
+    Varchar(Option<usize>), // the max length if the column has one
+    Boolean,
+    Uuid,
+    Timestamptz,
+    Date,
+    Json,
+    Jsonb,
+    Bytea,
+    Array(Box<PgType>),
+}
+
+impl PgType {
+    fn parse(data_type: &str) -> Result<Self> {
+        let data_type = data_type.trim().to_ascii_lowercase();
+
+        if let Some(element) = data_type.strip_suffix("[]") {
+            let element = Self::parse(element)?;
+            if let PgType::Array(_) = element {
+                return Err(anyhow!("Nested arrays are not supported"));
+            }
+            return Ok(PgType::Array(Box::new(element)));
+        }
+
+        // split off modifiers like varchar(255) or numeric(10, 2)
+        let (name, modifier) = match data_type.split_once('(') {
+            Some((name, modifier)) => (name.trim(), modifier.strip_suffix(')')),
+            None => (data_type.as_str(), None),
+        };
+
+        let pg_type = match name {
+            "smallint" | "int2" => PgType::SmallInt,
+            "integer" | "int" | "int4" => PgType::Integer,
+            "bigint" | "int8" => PgType::BigInt,
+            "real" | "float4" => PgType::Real,
+            "double precision" | "float8" => PgType::DoublePrecision,
+            "numeric" | "decimal" => PgType::Numeric,
+            "text" => PgType::Text,
+            "varchar" | "character varying" => {
+                let max_length = match modifier {
+                    Some(length) => Some(
+                        length
+                            .trim()
+                            .parse::<usize>()
+                            .map_err(|_| anyhow!("Invalid varchar length: {}", length))?,
+                    ),
+                    None => None,
+                };
+                PgType::Varchar(max_length)
+            }
+            "boolean" | "bool" => PgType::Boolean,
+            "uuid" => PgType::Uuid,
+            "timestamptz" | "timestamp with time zone" => PgType::Timestamptz,
+            "date" => PgType::Date,
+            "json" => PgType::Json,
+            "jsonb" => PgType::Jsonb,
+            "bytea" => PgType::Bytea,
+            _ => return Err(anyhow!("Unsupported column type: {}", data_type)),
+        };
+
+        Ok(pg_type)
+    }
+
+    // the type the placeholder gets cast to
+    // some values are bound as text (uuid, timestamptz, ...) and null has no type at all,
+    // so postgres always gets told what the value is
+    fn cast(&self) -> String {
+        match self {
+            PgType::Array(element) => format!("{}[]", element.name()),
+            other => other.name().to_string(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            PgType::SmallInt => "smallint",
+            PgType::Integer => "integer",
+            PgType::BigInt => "bigint",
+            PgType::Real => "real",
+            PgType::DoublePrecision => "double precision",
+            PgType::Numeric => "numeric",
+            PgType::Text => "text",
+            PgType::Varchar(_) => "varchar",
+            PgType::Boolean => "boolean",
+            PgType::Uuid => "uuid",
+            PgType::Timestamptz => "timestamptz",
+            PgType::Date => "date",
+            PgType::Json => "json",
+            PgType::Jsonb => "jsonb",
+            PgType::Bytea => "bytea",
+            PgType::Array(_) => "array",
+        }
+    }
+
+    fn convert(&self, column: &str, value: &Value) -> Result<Value> {
+        match self {
+            PgType::SmallInt => convert_integer(column, value, i16::MIN as i64, i16::MAX as i64),
+            PgType::Integer => convert_integer(column, value, i32::MIN as i64, i32::MAX as i64),
+            PgType::BigInt => {
+                if let Some(s) = value.as_str() {
+                    s.parse::<i64>()
+                        .map(|v| Value::Number(v.into()))
+                        .map_err(|_| anyhow!("Failed to convert {} to bigint", s))
+                } else if value.is_i64() || value.is_u64() {
+                    value
+                        .as_i64()
+                        .map(|v| Value::Number(v.into()))
+                        .ok_or(anyhow!(
+                            "Value for column {} is out of range for bigint",
+                            column
+                        ))
+                } else {
+                    Err(anyhow!("Expected a string or number for bigint"))
+                }
+            }
+            PgType::Real | PgType::DoublePrecision => {
+                let float = match value {
+                    Value::Number(number) => number.as_f64(),
+                    Value::String(s) => s.parse::<f64>().ok(),
+                    _ => None,
+                }
+                .ok_or(anyhow!(
+                    "Expected a number for {} column {}",
+                    self.name(),
+                    column
+                ))?;
+
+                if !float.is_finite() || (*self == PgType::Real && float.abs() > f32::MAX as f64) {
+                    return Err(anyhow!(
+                        "Value for column {} is out of range for {}",
+                        column,
+                        self.name()
+                    ));
+                }
+
+                serde_json::Number::from_f64(float)
+                    .map(Value::Number)
+                    .ok_or(anyhow!("Failed to convert {} to {}", float, self.name()))
+            }
+            PgType::Numeric => {
+                // keep numerics as strings so no precision gets lost on the way
+                let numeric = match value {
+                    Value::Number(number) => number.to_string(),
+                    Value::String(s) => s.trim().to_string(),
+                    _ => {
+                        return Err(anyhow!(
+                            "Expected a string or number for numeric column {}",
+                            column
+                        ))
+                    }
+                };
+
+                if !is_numeric_literal(&numeric) {
+                    return Err(anyhow!("Failed to convert {} to numeric", numeric));
+                }
+                Ok(Value::String(numeric))
+            }
+            PgType::Text => {
+                if value.is_string() {
+                    Ok(value.clone())
+                } else {
+                    Err(anyhow!("Expected a string for text column {}", column))
+                }
+            }
+            PgType::Varchar(max_length) => {
+                let s = value
+                    .as_str()
+                    .ok_or(anyhow!("Expected a string for varchar column {}", column))?;
+
+                if let Some(max_length) = max_length {
+                    if s.chars().count() > *max_length {
+                        return Err(anyhow!(
+                            "Value for column {} is longer than {} characters",
+                            column,
+                            max_length
+                        ));
+                    }
+                }
+                Ok(value.clone())
+            }
+            PgType::Boolean => {
+                if let Some(b) = value.as_bool() {
+                    Ok(Value::Bool(b))
+                } else {
+                    Err(anyhow!("Expected a boolean for column {}", column))
+                }
+            }
+            PgType::Uuid => {
+                let s = value
+                    .as_str()
+                    .ok_or(anyhow!("Expected a string for uuid column {}", column))?;
+                uuid::Uuid::parse_str(s)
+                    .map(|uuid| Value::String(uuid.to_string()))
+                    .map_err(|_| anyhow!("Failed to convert {} to uuid", s))
+            }
+            PgType::Timestamptz => {
+                let s = value
+                    .as_str()
+                    .ok_or(anyhow!("Expected an RFC 3339 string for column {}", column))?;
+                DateTime::parse_from_rfc3339(s)
+                    .map(|timestamp| Value::String(timestamp.to_rfc3339()))
+                    .map_err(|_| {
+                        anyhow!("Failed to convert {} to timestamptz, expected RFC 3339", s)
+                    })
+            }
+            PgType::Date => {
+                let s = value.as_str().ok_or(anyhow!(
+                    "Expected a YYYY-MM-DD string for column {}",
+                    column
+                ))?;
+                NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                    .map(|date| Value::String(date.to_string()))
+                    .map_err(|_| anyhow!("Failed to convert {} to date, expected YYYY-MM-DD", s))
+            }
+            PgType::Json | PgType::Jsonb => {
+                // any json document is fine, it gets bound as its text representation
+                Ok(Value::String(value.to_string()))
+            }
+            PgType::Bytea => {
+                let bytes: Vec<u8> = match value {
+                    Value::String(s) => {
+                        let hex = s.strip_prefix("\\x").ok_or(anyhow!(
+                            "Expected a hex string (\\\\x...) for bytea column {}",
+                            column
+                        ))?;
+                        decode_hex(hex)
+                            .ok_or(anyhow!("Invalid hex string for bytea column {}", column))?
+                    }
+                    Value::Array(values) => values
+                        .iter()
+                        .map(|v| v.as_u64().filter(|b| *b <= u8::MAX as u64).map(|b| b as u8))
+                        .collect::<Option<Vec<u8>>>()
+                        .ok_or(anyhow!(
+                            "Expected an array of bytes for bytea column {}",
+                            column
+                        ))?,
+                    _ => {
+                        return Err(anyhow!(
+                            "Expected a hex string or an array of bytes for bytea column {}",
+                            column
+                        ))
+                    }
+                };
+
+                let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                Ok(Value::String(format!("\\x{}", hex)))
+            }
+            PgType::Array(element) => {
+                let values = value
+                    .as_array()
+                    .ok_or(anyhow!("Expected an array for column {}", column))?;
+
+                let mut elements = Vec::new();
+                for value in values {
+                    // array elements may always be NULL
+                    let converted = if value.is_null() {
+                        Value::Null
+                    } else {
+                        element.convert(column, value)?
+                    };
+                    elements.push(array_element_literal(&converted));
+                }
+
+                // bound as a postgres array literal, e.g. {1,2,3} or {"a","b"}
+                Ok(Value::String(format!("{{{}}}", elements.join(","))))
+            }
+        }
+    }
+}
+
+impl ColumnType {
+    pub fn new(data_type: &str) -> Self {
+        Self {
+            data_type: data_type.to_string(),
+            nullable: false,
+        }
+    }
+
+    pub fn nullable(data_type: &str) -> Self {
+        Self {
+            data_type: data_type.to_string(),
+            nullable: true,
+        }
+    }
+
+    // checks the value against the column type and converts it into
+    // the representation it gets bound with
+    pub fn convert(&self, column: &str, value: &Value) -> Result<Value> {
+        let pg_type = PgType::parse(&self.data_type)?;
+
+        if value.is_null() {
+            if self.nullable {
+                return Ok(Value::Null);
+            }
+            return Err(anyhow!("Column {} does not accept null", column));
+        }
+
+        pg_type
+            .convert(column, value)
+            .map_err(|e| DatabaseError::type_conversion(e.to_string()).into())
+    }
+
+    // the placeholder for a value of this column, e.g. $1::uuid
+    pub fn placeholder(&self, bind_index: usize) -> Result<String> {
+        Ok(format!(
+            "${}::{}",
+            bind_index,
+            PgType::parse(&self.data_type)?.cast()
+        ))
+    }
+
+    pub fn is_numeric(&self) -> bool {
+        matches!(
+            PgType::parse(&self.data_type),
+            Ok(PgType::SmallInt
+                | PgType::Integer
+                | PgType::BigInt
+                | PgType::Real
+                | PgType::DoublePrecision
+                | PgType::Numeric)
+        )
+    }
+
+    pub fn is_text(&self) -> bool {
+        matches!(
+            PgType::parse(&self.data_type),
+            Ok(PgType::Text | PgType::Varchar(_))
+        )
+    }
+
+    pub fn is_json(&self) -> bool {
+        matches!(
+            PgType::parse(&self.data_type),
+            Ok(PgType::Json | PgType::Jsonb)
+        )
+    }
+
+    pub fn is_jsonb(&self) -> bool {
+        matches!(PgType::parse(&self.data_type), Ok(PgType::Jsonb))
+    }
+}
+
+impl From<&str> for ColumnType {
+    fn from(data_type: &str) -> Self {
+        ColumnType::new(data_type)
+    }
+}
+
+fn convert_integer(column: &str, value: &Value, min: i64, max: i64) -> Result<Value> {
+    let integer = match value {
+        Value::Number(number) => number.as_i64(),
+        Value::String(s) => s.parse::<i64>().ok(),
+        _ => return Err(anyhow!("Expected a string or number for column {}", column)),
+    }
+    .ok_or(anyhow!("Failed to convert {} to an integer", value))?;
+
+    if integer < min || integer > max {
+        return Err(anyhow!("Value for column {} is out of range", column));
+    }
+    Ok(Value::Number(integer.into()))
+}
+
+fn is_numeric_literal(s: &str) -> bool {
+    let s = s.strip_prefix(['+', '-']).unwrap_or(s);
+    if s.eq_ignore_ascii_case("nan") {
+        return true;
+    }
+
+    let (mantissa, exponent) = match s.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, Some(exponent)),
+        None => (s, None),
+    };
+
+    let mut parts = mantissa.splitn(2, '.');
+    let integer_part = parts.next().unwrap_or("");
+    let fraction_part = parts.next().unwrap_or("");
+
+    let digits = |part: &str| part.chars().all(|c| c.is_ascii_digit());
+    let mantissa_valid = digits(integer_part)
+        && digits(fraction_part)
+        && !(integer_part.is_empty() && fraction_part.is_empty());
+
+    let exponent_valid = match exponent {
+        Some(exponent) => {
+            let exponent = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+            !exponent.is_empty() && digits(exponent)
+        }
+        None => true,
+    };
+
+    mantissa_valid && exponent_valid
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+        })
+        .collect()
+}
+
+fn array_element_literal(value: &Value) -> String {
+    match value {
+        Value::Null => String::from("NULL"),
+        Value::String(s) => format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\"")),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parse_known_types_and_aliases() {
+        assert_eq!(PgType::parse("int8").unwrap(), PgType::BigInt);
+        assert_eq!(PgType::parse(" INTEGER ").unwrap(), PgType::Integer);
+        assert_eq!(
+            PgType::parse("timestamp with time zone").unwrap(),
+            PgType::Timestamptz
+        );
+        assert_eq!(PgType::parse("numeric(10, 2)").unwrap(), PgType::Numeric);
+        assert_eq!(
+            PgType::parse("varchar(255)").unwrap(),
+            PgType::Varchar(Some(255))
+        );
+        assert_eq!(
+            PgType::parse("character varying").unwrap(),
+            PgType::Varchar(None)
+        );
+        assert_eq!(
+            PgType::parse("text[]").unwrap(),
+            PgType::Array(Box::new(PgType::Text))
+        );
+    }
+
+    #[test]
+    fn parse_rejects_unsupported_types() {
+        assert_eq!(
+            PgType::parse("int[][]").unwrap_err().to_string(),
+            "Nested arrays are not supported"
+        );
+        assert_eq!(
+            PgType::parse("varchar(abc)").unwrap_err().to_string(),
+            "Invalid varchar length: abc"
+        );
+        assert_eq!(
+            PgType::parse("point").unwrap_err().to_string(),
+            "Unsupported column type: point"
+        );
+    }
+
+    #[test]
+    fn convert_integers() {
+        assert_eq!(
+            PgType::BigInt.convert("c", &json!("12")).unwrap(),
+            json!(12)
+        );
+        assert_eq!(PgType::BigInt.convert("c", &json!(-3)).unwrap(), json!(-3));
+        assert!(PgType::BigInt.convert("c", &json!(u64::MAX)).is_err());
+        assert!(PgType::BigInt.convert("c", &json!(1.5)).is_err());
+        assert_eq!(
+            PgType::SmallInt.convert("c", &json!("-32768")).unwrap(),
+            json!(-32768)
+        );
+        assert_eq!(
+            PgType::SmallInt
+                .convert("c", &json!(32768))
+                .unwrap_err()
+                .to_string(),
+            "Value for column c is out of range"
+        );
+        assert!(PgType::Integer.convert("c", &json!(true)).is_err());
+    }
+
+    #[test]
+    fn convert_floats_and_numerics() {
+        assert_eq!(
+            PgType::DoublePrecision.convert("c", &json!("1.5")).unwrap(),
+            json!(1.5)
+        );
+        assert!(PgType::Real.convert("c", &json!(1e39)).is_err());
+        // numerics stay strings so no precision gets lost
+        assert_eq!(
+            PgType::Numeric
+                .convert("c", &json!(" 12345678901234567890.5 "))
+                .unwrap(),
+            json!("12345678901234567890.5")
+        );
+        assert_eq!(PgType::Numeric.convert("c", &json!(2)).unwrap(), json!("2"));
+        assert!(PgType::Numeric.convert("c", &json!("1,5")).is_err());
+    }
+
+    #[test]
+    fn convert_strings() {
+        assert_eq!(
+            PgType::Varchar(Some(3))
+                .convert("c", &json!("äöü"))
+                .unwrap(),
+            json!("äöü")
+        );
+        assert_eq!(
+            PgType::Varchar(Some(3))
+                .convert("c", &json!("abcd"))
+                .unwrap_err()
+                .to_string(),
+            "Value for column c is longer than 3 characters"
+        );
+        assert!(PgType::Text.convert("c", &json!(1)).is_err());
+        assert_eq!(
+            PgType::Uuid
+                .convert("c", &json!("67E55044-10B1-426F-9247-BB680E5FE0C8"))
+                .unwrap(),
+            json!("67e55044-10b1-426f-9247-bb680e5fe0c8")
+        );
+        assert!(PgType::Uuid.convert("c", &json!("nope")).is_err());
+    }
+
+    #[test]
+    fn convert_dates_and_timestamps() {
+        assert_eq!(
+            PgType::Timestamptz
+                .convert("c", &json!("2024-01-02T03:04:05Z"))
+                .unwrap(),
+            json!("2024-01-02T03:04:05+00:00")
+        );
+        assert!(PgType::Timestamptz
+            .convert("c", &json!("2024-01-02"))
+            .is_err());
+        assert_eq!(
+            PgType::Date.convert("c", &json!("2024-02-29")).unwrap(),
+            json!("2024-02-29")
+        );
+        assert!(PgType::Date.convert("c", &json!("2023-02-29")).is_err());
+    }
+
+    #[test]
+    fn convert_json_and_bytea() {
+        assert_eq!(
+            PgType::Jsonb.convert("c", &json!({"a": [1]})).unwrap(),
+            json!(r#"{"a":[1]}"#)
+        );
+        assert_eq!(
+            PgType::Bytea.convert("c", &json!("\\x0aFF")).unwrap(),
+            json!("\\x0aff")
+        );
+        assert_eq!(
+            PgType::Bytea.convert("c", &json!([1, 255])).unwrap(),
+            json!("\\x01ff")
+        );
+        assert!(PgType::Bytea.convert("c", &json!("0aff")).is_err());
+        assert!(PgType::Bytea.convert("c", &json!("\\x0")).is_err());
+        assert!(PgType::Bytea.convert("c", &json!([256])).is_err());
+    }
+
+    #[test]
+    fn convert_arrays() {
+        let integers = PgType::Array(Box::new(PgType::Integer));
+        assert_eq!(
+            integers.convert("c", &json!([1, "2", null])).unwrap(),
+            json!("{1,2,NULL}")
+        );
+        assert!(integers.convert("c", &json!([1, "x"])).is_err());
+
+        let texts = PgType::Array(Box::new(PgType::Text));
+        assert_eq!(
+            texts
+                .convert("c", &json!(["a", "b \"c\"", "d\\e"]))
+                .unwrap(),
+            json!(r#"{"a","b \"c\"","d\\e"}"#)
+        );
+    }
+
+    #[test]
+    fn numeric_literals() {
+        for literal in ["1", "-1.5", "+.5", "5.", "1e10", "1.5E-3", "NaN", "-nan"] {
+            assert!(is_numeric_literal(literal), "{}", literal);
+        }
+        for literal in [
+            "", ".", "-", "1e", "e5", "1.2.3", "1,5", "0x10", "1 2", "inf",
+        ] {
+            assert!(!is_numeric_literal(literal), "{}", literal);
+        }
+    }
+
+    #[test]
+    fn array_element_literals() {
+        assert_eq!(array_element_literal(&Value::Null), "NULL");
+        assert_eq!(array_element_literal(&json!(1.5)), "1.5");
+        assert_eq!(array_element_literal(&json!(true)), "true");
+        assert_eq!(
+            array_element_literal(&json!(r#"a "b" \c"#)),
+            r#""a \"b\" \\c""#
+        );
+    }
+
+    #[test]
+    fn column_type_convert_and_placeholder() {
+        let nullable = ColumnType::nullable("uuid");
+        assert_eq!(nullable.convert("c", &Value::Null).unwrap(), Value::Null);
+        assert_eq!(nullable.placeholder(3).unwrap(), "$3::uuid");
+
+        assert_eq!(
+            ColumnType::new("bigint")
+                .convert("c", &Value::Null)
+                .unwrap_err()
+                .to_string(),
+            "Column c does not accept null"
+        );
+        assert_eq!(
+            ColumnType::new("varchar(10)").placeholder(1).unwrap(),
+            "$1::varchar"
+        );
+        assert_eq!(
+            ColumnType::new("int4[]").placeholder(2).unwrap(),
+            "$2::integer[]"
+        );
+    }
+}
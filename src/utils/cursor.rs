@@ -0,0 +1,110 @@
+use crate::models::db::{Cursor, OrderBy};
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde_json::Value;
+
+impl Cursor {
+    // the cursor to continue right after row, which needs a value for every sort key
+    pub fn after_row(order_by: &[OrderBy], row: &Value) -> Result<Self> {
+        let values = order_by
+            .iter()
+            .map(|key| {
+                row.get(&key.column).cloned().ok_or(anyhow!(
+                    "Column {} has to be selected to continue with a cursor",
+                    key.column
+                ))
+            })
+            .collect::<Result<Vec<Value>>>()?;
+
+        Ok(Self {
+            order_by: order_by.to_vec(),
+            values,
+        })
+    }
+
+    // the cursor is only meant to be passed back, the base64 just keeps it url safe
+    pub fn encode(&self) -> Result<String> {
+        Ok(URL_SAFE_NO_PAD.encode(serde_json::to_vec(self)?))
+    }
+
+    pub fn decode(cursor: &str) -> Result<Self> {
+        let bytes = URL_SAFE_NO_PAD
+            .decode(cursor)
+            .map_err(|_| anyhow!("Invalid cursor"))?;
+        serde_json::from_slice(&bytes).map_err(|_| anyhow!("Invalid cursor"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::db::{NullsOrder, OrderDirection};
+    use serde_json::json;
+
+    fn order_by() -> Vec<OrderBy> {
+        vec![
+            OrderBy {
+                column: String::from("score"),
+                direction: OrderDirection::Desc,
+                nulls: Some(NullsOrder::Last),
+            },
+            OrderBy {
+                column: String::from("id"),
+                direction: OrderDirection::Asc,
+                nulls: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn after_row_takes_the_sort_key_values() {
+        let row = json!({"id": 3, "score": null, "name": "c"});
+
+        let cursor = Cursor::after_row(&order_by(), &row).unwrap();
+
+        assert_eq!(cursor.order_by, order_by());
+        assert_eq!(cursor.values, vec![Value::Null, json!(3)]);
+    }
+
+    #[test]
+    fn after_row_requires_selected_sort_keys() {
+        let row = json!({"id": 3});
+
+        let error = Cursor::after_row(&order_by(), &row).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Column score has to be selected to continue with a cursor"
+        );
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let cursor = Cursor {
+            order_by: order_by(),
+            values: vec![json!("a \"quoted\" value/+"), json!(-1.5)],
+        };
+
+        let encoded = cursor.encode().unwrap();
+        // url safe without padding
+        assert!(!encoded.contains(['+', '/', '=']));
+
+        let decoded = Cursor::decode(&encoded).unwrap();
+        assert_eq!(decoded.order_by, cursor.order_by);
+        assert_eq!(decoded.values, cursor.values);
+    }
+
+    #[test]
+    fn decode_rejects_garbage() {
+        assert_eq!(
+            Cursor::decode("not a cursor").unwrap_err().to_string(),
+            "Invalid cursor"
+        );
+        // valid base64, but not a cursor
+        let encoded = URL_SAFE_NO_PAD.encode(b"{\"values\": 1}");
+        assert_eq!(
+            Cursor::decode(&encoded).unwrap_err().to_string(),
+            "Invalid cursor"
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use crate::models::db::{DatabaseError, DatabaseErrorCode, DatabaseResponse};
+use std::fmt;
+
+impl DatabaseErrorCode {
+    // https://www.postgresql.org/docs/current/errcodes-appendix.html
+    pub fn from_sqlstate(sqlstate: &str) -> Self {
+        match sqlstate {
+            "23505" => DatabaseErrorCode::UniqueViolation,
+            "23503" => DatabaseErrorCode::ForeignKeyViolation,
+            "42501" => DatabaseErrorCode::PermissionDenied,
+            // not null and check violations are bad input as well
+            "23502" | "23514" => DatabaseErrorCode::Validation,
+            // class 22 (data exception): invalid text representation, out of range, too long, ...
+            _ if sqlstate.starts_with("22") => DatabaseErrorCode::TypeConversion,
+            _ => DatabaseErrorCode::Internal,
+        }
+    }
+
+    // the http status a handler should answer with
+    pub fn status_code(&self) -> u16 {
+        match self {
+            DatabaseErrorCode::UniqueViolation | DatabaseErrorCode::ForeignKeyViolation => 409,
+            DatabaseErrorCode::NotFound => 404,
+            DatabaseErrorCode::Validation | DatabaseErrorCode::TypeConversion => 400,
+            DatabaseErrorCode::PermissionDenied => 403,
+            DatabaseErrorCode::Internal => 500,
+        }
+    }
+}
+
+impl DatabaseError {
+    pub fn new(code: DatabaseErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn validation(message: impl Into<String>) -> Self {
+        Self::new(DatabaseErrorCode::Validation, message)
+    }
+
+    pub fn permission_denied(message: impl Into<String>) -> Self {
+        Self::new(DatabaseErrorCode::PermissionDenied, message)
+    }
+
+    pub fn type_conversion(message: impl Into<String>) -> Self {
+        Self::new(DatabaseErrorCode::TypeConversion, message)
+    }
+
+    pub fn from_sqlx(error: &sqlx::Error) -> Self {
+        let code = match error {
+            sqlx::Error::Database(db_error) => db_error
+                .code()
+                .map(|sqlstate| DatabaseErrorCode::from_sqlstate(&sqlstate))
+                .unwrap_or_default(),
+            sqlx::Error::RowNotFound => DatabaseErrorCode::NotFound,
+            sqlx::Error::ColumnDecode { .. } | sqlx::Error::Decode(_) => {
+                DatabaseErrorCode::TypeConversion
+            }
+            _ => DatabaseErrorCode::Internal,
+        };
+        Self::new(code, error.to_string())
+    }
+
+    // errors that are neither a DatabaseError nor come from sqlx were raised
+    // while checking and building the request, so they count as Validation
+    pub fn from_anyhow(error: &anyhow::Error) -> Self {
+        let message = error.to_string();
+
+        for cause in error.chain() {
+            if let Some(database_error) = cause.downcast_ref::<DatabaseError>() {
+                return Self::new(database_error.code, message);
+            }
+            if let Some(sqlx_error) = cause.downcast_ref::<sqlx::Error>() {
+                return Self::new(Self::from_sqlx(sqlx_error).code, message);
+            }
+        }
+
+        Self::validation(message)
+    }
+
+    // puts more context in front of the message, the code stays the same
+    pub fn prefixed(self, prefix: &str) -> Self {
+        Self::new(self.code, format!("{}: {}", prefix, self.message))
+    }
+}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for DatabaseError {}
+
+impl<T> From<DatabaseError> for DatabaseResponse<T> {
+    fn from(error: DatabaseError) -> Self {
+        DatabaseResponse::Error {
+            error: error.message,
+            code: error.code,
+        }
+    }
+}
@@ -0,0 +1,610 @@
+use crate::db::{ModelFactory, ModelRegistry};
+use crate::models::db::{
+    BuildQuery, Cursor, DatabaseAction, DatabaseError, DatabaseErrorCode, DatabaseReply,
+    DatabaseRequest, DatabaseResponse, DeleteAction, PageInfo, QueryBuilder, QueryExecutor,
+    QueryLimits, QueryOutput, QueryPolicy, SchemaCache, TableColumns,
+};
+use crate::utils::query_builder::check_model_declares_columns;
+use anyhow::Result;
+use serde_json::Value;
+use sqlx::postgres::PgArguments;
+use sqlx::query::Query;
+use sqlx::{Connection, PgConnection, PgPool, Postgres, Row};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+impl QueryExecutor {
+    pub fn new(pool: PgPool, model_registry: Arc<ModelRegistry>) -> Self {
+        Self {
+            pool,
+            model_registry,
+            schema_cache: None,
+            policy: QueryPolicy::default(),
+            limits: QueryLimits::default(),
+            allowed_tables: HashSet::new(),
+        }
+    }
+
+    // tables that are not registered but may still be queried, e.g. ones without a model
+    pub fn with_allowed_tables(mut self, allowed_tables: HashSet<String>) -> Self {
+        self.allowed_tables = allowed_tables;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: QueryLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_policy(mut self, policy: QueryPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    // lets the executor look up the table columns itself
+    pub fn with_schema_cache(mut self, schema_cache: Arc<SchemaCache>) -> Self {
+        self.schema_cache = Some(schema_cache);
+        self
+    }
+
+    // validates, builds and runs the request
+    // owner is the uid of the authenticated caller, owner-scoped tables are limited to their rows
+    // without table_columns they are taken from the schema cache,
+    // or the registered model if there is no cache
+    pub async fn execute(
+        &self,
+        request: &DatabaseRequest,
+        owner: Option<Value>,
+        table_columns: Option<TableColumns>,
+    ) -> Result<QueryOutput> {
+        let mut conn = self.pool.acquire().await?;
+        self.execute_on(&mut conn, request, owner, table_columns)
+            .await
+    }
+
+    // same as execute, but gives back a response that can be sent as it is
+    pub async fn handle(
+        &self,
+        request: &DatabaseRequest,
+        owner: Option<Value>,
+        table_columns: Option<TableColumns>,
+    ) -> DatabaseReply<Value> {
+        let response = match self.execute(request, owner, table_columns).await {
+            Ok(output) => output.into_response(),
+            Err(e) => DatabaseError::from_anyhow(&e).into(),
+        };
+
+        DatabaseReply {
+            request_id: request.request_id.clone(),
+            response,
+        }
+    }
+
+    pub async fn execute_on(
+        &self,
+        conn: &mut PgConnection,
+        request: &DatabaseRequest,
+        owner: Option<Value>,
+        table_columns: Option<TableColumns>,
+    ) -> Result<QueryOutput> {
+        let mut request = request.clone();
+        // whatever owner the request came with, only the one of the caller counts
+        request.owner = owner;
+        request.validate().map_err(DatabaseError::validation)?;
+
+        if let DatabaseAction::Transaction(mut requests) = request.action {
+            // the flags of the transaction count for every request of it
+            for step in requests.iter_mut() {
+                step.dry_run |= request.dry_run;
+                step.explain |= request.explain;
+                // a step cannot run on behalf of someone else than the transaction
+                step.owner = request.owner.clone();
+            }
+            return self.execute_transaction(conn, requests).await;
+        }
+
+        self.execute_validated(conn, request, table_columns).await
+    }
+
+    async fn execute_transaction(
+        &self,
+        conn: &mut PgConnection,
+        requests: Vec<DatabaseRequest>,
+    ) -> Result<QueryOutput> {
+        let mut tx = conn.begin().await?;
+        let mut outputs = Vec::new();
+
+        for (i, request) in requests.into_iter().enumerate() {
+            // returning early drops tx, which rolls back everything done so far
+            let output = self
+                .execute_validated(&mut tx, request, None)
+                .await
+                .map_err(|e| {
+                    DatabaseError::from_anyhow(&e)
+                        .prefixed(&format!("request {} of the transaction failed", i))
+                })?;
+            outputs.push(output);
+        }
+
+        tx.commit().await?;
+
+        Ok(QueryOutput::Transaction(outputs))
+    }
+
+    // the request has to be validated already and must not be a transaction
+    async fn execute_validated(
+        &self,
+        conn: &mut PgConnection,
+        request: DatabaseRequest,
+        table_columns: Option<TableColumns>,
+    ) -> Result<QueryOutput> {
+        let table_columns = match table_columns {
+            Some(table_columns) => Some(table_columns),
+            None => self.lookup_table_columns(&request).await?,
+        };
+        let atomic = request.atomic;
+        let (dry_run, explain) = (request.dry_run, request.explain);
+        // only reads can count_total, validate() made sure of that
+        let total_request = (request.count_total && !dry_run && !explain)
+            .then(|| (request.clone(), table_columns.clone()));
+
+        let mut query_builder = self.query_builder(request, table_columns);
+        // a step of a transaction that is refused rolls back the steps before it
+        self.policy
+            .check(&query_builder)
+            .map_err(DatabaseError::permission_denied)?;
+
+        if dry_run || explain {
+            let queries = if query_builder.action == DatabaseAction::BulkInsert {
+                query_builder.build_bulk_insert_queries()?
+            } else {
+                vec![query_builder.build_query()?]
+            };
+            return describe_queries(conn, queries, explain).await;
+        }
+
+        if query_builder.action == DatabaseAction::BulkInsert {
+            return self
+                .execute_bulk_insert(conn, &mut query_builder, atomic)
+                .await;
+        }
+
+        // only set past the dry run, which shows the limit as it was requested
+        query_builder.fetch_has_more = query_builder.action.is_read();
+        let (query, bind_params) = query_builder.build_query()?;
+
+        let total = match total_request {
+            Some((request, table_columns)) => {
+                Some(self.count_total(conn, request, table_columns).await?)
+            }
+            None => None,
+        };
+
+        if let Some(factory) = self.model_factory(&query_builder) {
+            let rows = bind_values(sqlx::query(&query), &bind_params)
+                .fetch_all(&mut *conn)
+                .await?;
+
+            let models = rows
+                .iter()
+                .map(factory)
+                .collect::<Result<Vec<_>, sqlx::Error>>()?;
+
+            return into_page(QueryOutput::Models(models), &query_builder, total);
+        }
+
+        if query_builder.action.is_read() {
+            let rows = fetch_json_rows(conn, &query, &bind_params).await?;
+            return into_page(QueryOutput::Rows(rows), &query_builder, total);
+        }
+
+        if query_builder.returning.is_some() {
+            let rows = fetch_json_rows(conn, &query, &bind_params).await?;
+            check_owned_rows_found(&query_builder, rows.len() as u64)?;
+            return Ok(QueryOutput::Rows(rows));
+        }
+
+        let result = bind_values(sqlx::query(&query), &bind_params)
+            .execute(&mut *conn)
+            .await?;
+        check_owned_rows_found(&query_builder, result.rows_affected())?;
+
+        Ok(QueryOutput::RowsAffected(result.rows_affected()))
+    }
+
+    fn query_builder(
+        &self,
+        request: DatabaseRequest,
+        table_columns: Option<TableColumns>,
+    ) -> QueryBuilder {
+        let mut query_builder = QueryBuilder::from_request(request, table_columns);
+        query_builder.model_registry = Some(self.model_registry.clone());
+        query_builder.limits = Some(self.limits.table_limits(&query_builder.table));
+        // the schema cache only provides column types, it does not make a table queryable
+        query_builder.allowed_tables = Some(self.allowed_tables.clone());
+        query_builder
+    }
+
+    // every row the read matches, no matter which page of them was asked for
+    async fn count_total(
+        &self,
+        conn: &mut PgConnection,
+        mut request: DatabaseRequest,
+        table_columns: Option<TableColumns>,
+    ) -> Result<u64> {
+        if let Some(filters) = request.filters.as_mut() {
+            filters.order_by = None;
+            filters.limit = None;
+            filters.offset = None;
+            filters.cursor = None;
+        }
+
+        let mut query_builder = self.query_builder(request, table_columns);
+        // without limits no default limit gets applied
+        query_builder.limits = None;
+        let (query, bind_params) = query_builder.build_query()?;
+
+        let row = bind_values(
+            sqlx::query(&format!(
+                "SELECT count(*) AS total FROM ({}) AS counted",
+                query
+            )),
+            &bind_params,
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+
+        Ok(row.try_get::<i64, _>("total")? as u64)
+    }
+
+    // bulk inserts can be split into several statements, see build_bulk_insert_queries
+    async fn execute_bulk_insert(
+        &self,
+        conn: &mut PgConnection,
+        query_builder: &mut QueryBuilder,
+        atomic: bool,
+    ) -> Result<QueryOutput> {
+        let queries = query_builder.build_bulk_insert_queries()?;
+        let returning = query_builder.returning.is_some();
+
+        if atomic && queries.len() > 1 {
+            // inside of a Transaction action this becomes a savepoint
+            let mut tx = conn.begin().await?;
+            let output = run_bulk_insert(&mut tx, &queries, returning).await?;
+            tx.commit().await?;
+            return Ok(output);
+        }
+
+        run_bulk_insert(conn, &queries, returning).await
+    }
+
+    async fn lookup_table_columns(
+        &self,
+        request: &DatabaseRequest,
+    ) -> Result<Option<TableColumns>> {
+        let table = &request.table;
+        if let Some(schema_cache) = &self.schema_cache {
+            if let Some(table_columns) = schema_cache.table_columns(table).await {
+                return Ok(Some(table_columns));
+            }
+        }
+
+        let table_columns = self.model_registry.table_columns(table);
+        // reads get by without them, writes have to check and convert their values
+        // (pass table_columns or attach a SchemaCache for those tables)
+        if let (None, Some(entry)) = (&table_columns, self.model_registry.get(table)) {
+            if !request.action.is_read() {
+                check_model_declares_columns(&entry.columns, table)?;
+            }
+        }
+
+        Ok(table_columns)
+    }
+
+    // plain retrieves of a registered table get decoded into their model,
+    // everything else (projections, joins, ...) does not fit the model anymore
+    fn model_factory(&self, query_builder: &QueryBuilder) -> Option<ModelFactory> {
+        if query_builder.action != DatabaseAction::Retrieve
+            || query_builder.columns.is_some()
+            || query_builder.joins.is_some()
+        {
+            return None;
+        }
+
+        // sensitive columns are not selected, so the model could not be built from the row
+        self.model_registry
+            .get(&query_builder.table)
+            .filter(|entry| !entry.has_sensitive_columns())
+            .map(|entry| entry.factory)
+    }
+}
+
+// an owner-scoped update or delete that matched nothing either targeted rows that do not exist
+// or rows of someone else, both look the same to the owner so neither is given away
+fn check_owned_rows_found(query_builder: &QueryBuilder, rows_affected: u64) -> Result<()> {
+    let targets_rows = matches!(
+        query_builder.action,
+        DatabaseAction::Update
+            | DatabaseAction::Delete(DeleteAction::DeleteValue | DeleteAction::Purge)
+    );
+
+    if targets_rows && rows_affected == 0 && query_builder.owner_column().is_some() {
+        return Err(DatabaseError::new(
+            DatabaseErrorCode::NotFound,
+            format!("No matching rows found in table {}", query_builder.table),
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+// drops the extra row fetch_has_more asked for and tells if it was there
+fn into_page(
+    output: QueryOutput,
+    query_builder: &QueryBuilder,
+    total: Option<u64>,
+) -> Result<QueryOutput> {
+    let limit = match query_builder.effective_limit()? {
+        Some(limit) if query_builder.fetch_has_more => limit,
+        _ => return Ok(output),
+    };
+
+    let (output, has_more, last_row) = match output {
+        QueryOutput::Models(mut models) => {
+            let has_more = models.len() > limit as usize;
+            models.truncate(limit as usize);
+            let last_row = models.last().map(|model| model.as_value());
+            (QueryOutput::Models(models), has_more, last_row)
+        }
+        QueryOutput::Rows(mut rows) => {
+            let has_more = rows.len() > limit as usize;
+            rows.truncate(limit as usize);
+            let last_row = rows.last().cloned();
+            (QueryOutput::Rows(rows), has_more, last_row)
+        }
+        output => return Ok(output),
+    };
+
+    let filters = query_builder.filters.clone().unwrap_or_default();
+
+    // ranked searches order by something that is not part of the rows
+    let ranked = filters.search.as_ref().is_some_and(|search| search.rank);
+    let cursor_supported = !ranked && query_builder.cursor_keys_grouped();
+    let next_cursor = match (filters.order_by, last_row) {
+        (Some(order_by), Some(last_row))
+            if has_more && cursor_supported && !order_by.is_empty() =>
+        {
+            // the read did not ask for a cursor, so rows without every sort key
+            // (not selected, qualified, ...) just get no next cursor instead of an error
+            match Cursor::after_row(&order_by, &last_row) {
+                Ok(cursor) => Some(cursor.encode()?),
+                Err(_) => None,
+            }
+        }
+        _ => None,
+    };
+
+    Ok(QueryOutput::Page {
+        output: Box::new(output),
+        page: PageInfo {
+            limit,
+            offset: filters.offset.unwrap_or(0),
+            has_more,
+            next_cursor,
+            cursor: filters.cursor,
+            total,
+        },
+    })
+}
+
+// one {"sql", "params"} row per statement, with its "plan" if explain is set
+// EXPLAIN without ANALYZE only plans the statement, so this is fine for writes as well
+async fn describe_queries(
+    conn: &mut PgConnection,
+    queries: Vec<BuildQuery>,
+    explain: bool,
+) -> Result<QueryOutput> {
+    let mut rows = Vec::new();
+
+    for (query, bind_params) in queries {
+        let mut row = serde_json::json!({
+            "sql": query,
+            "params": bind_params,
+        });
+
+        if explain {
+            let plan: Value = bind_values(
+                sqlx::query(&format!("EXPLAIN (FORMAT JSON) {}", query)),
+                &bind_params,
+            )
+            .fetch_one(&mut *conn)
+            .await?
+            .try_get(0)?;
+            row["plan"] = plan;
+        }
+
+        rows.push(row);
+    }
+
+    Ok(QueryOutput::Rows(rows))
+}
+
+async fn run_bulk_insert(
+    conn: &mut PgConnection,
+    queries: &[BuildQuery],
+    returning: bool,
+) -> Result<QueryOutput> {
+    let mut rows = Vec::new();
+    let mut rows_affected = 0;
+
+    for (i, (query, bind_params)) in queries.iter().enumerate() {
+        let result = if returning {
+            fetch_json_rows(conn, query, bind_params)
+                .await
+                .map(|chunk_rows| rows.extend(chunk_rows))
+        } else {
+            bind_values(sqlx::query(query), bind_params)
+                .execute(&mut *conn)
+                .await
+                .map(|result| rows_affected += result.rows_affected())
+                .map_err(anyhow::Error::from)
+        };
+
+        result.map_err(|e| {
+            DatabaseError::from_anyhow(&e).prefixed(&format!(
+                "statement {} of {} of the bulk insert failed",
+                i + 1,
+                queries.len()
+            ))
+        })?;
+    }
+
+    if returning {
+        Ok(QueryOutput::Rows(rows))
+    } else {
+        Ok(QueryOutput::RowsAffected(rows_affected))
+    }
+}
+
+// let postgres turn the rows into json, this way we dont need
+// to know how to decode every single column type ourself
+async fn fetch_json_rows(
+    conn: &mut PgConnection,
+    query: &str,
+    bind_params: &[Value],
+) -> Result<Vec<Value>> {
+    let json_query = format!(
+        "WITH result AS ({}) SELECT to_jsonb(result) AS row FROM result",
+        query
+    );
+
+    let rows = bind_values(sqlx::query(&json_query), bind_params)
+        .fetch_all(&mut *conn)
+        .await?;
+
+    let rows = rows
+        .iter()
+        .map(|row| row.try_get::<Value, _>("row"))
+        .collect::<Result<Vec<Value>, sqlx::Error>>()?;
+
+    Ok(rows)
+}
+
+impl QueryOutput {
+    pub fn into_response(self) -> DatabaseResponse<Value> {
+        match self {
+            QueryOutput::Models(models) => {
+                DatabaseResponse::Data(models.iter().map(|model| model.as_value()).collect())
+            }
+            QueryOutput::Rows(rows) => DatabaseResponse::Data(rows),
+            QueryOutput::RowsAffected(rows_affected) => DatabaseResponse::Status {
+                status: format!("{} rows affected", rows_affected),
+                rows_affected: Some(rows_affected),
+            },
+            QueryOutput::Transaction(outputs) => DatabaseResponse::Data(
+                outputs
+                    .into_iter()
+                    .map(|output| serde_json::json!(output.into_response()))
+                    .collect(),
+            ),
+            QueryOutput::Page { output, page } => match output.into_response() {
+                DatabaseResponse::Data(data) => DatabaseResponse::Page { data, page },
+                response => response,
+            },
+        }
+    }
+}
+
+// binds every value with the postgres type that matches its json type,
+// the QueryBuilder casts the placeholders to the actual column type
+fn bind_values<'q>(
+    mut query: Query<'q, Postgres, PgArguments>,
+    values: &[Value],
+) -> Query<'q, Postgres, PgArguments> {
+    for value in values {
+        query = match value {
+            Value::Null => query.bind(None::<String>),
+            Value::Bool(b) => query.bind(*b),
+            Value::Number(number) => match number.as_i64() {
+                Some(integer) => query.bind(integer),
+                None => query.bind(number.as_f64()),
+            },
+            Value::String(s) => query.bind(s.clone()),
+            // only reaches here for untyped filters, postgres parses the text itself
+            other => query.bind(other.to_string()),
+        };
+    }
+    query
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::db::{Filters, OrderBy, OrderDirection};
+    use serde_json::json;
+
+    fn limited_read(order_by: &str) -> QueryBuilder {
+        let mut query_builder = QueryBuilder::new(
+            String::from("users"),
+            DatabaseAction::Retrieve,
+            None,
+            None,
+            None,
+            Some(Filters {
+                order_by: Some(vec![OrderBy {
+                    column: order_by.to_string(),
+                    direction: OrderDirection::Asc,
+                    nulls: None,
+                }]),
+                limit: Some(1),
+                ..Default::default()
+            }),
+        );
+        query_builder.fetch_has_more = true;
+        query_builder
+    }
+
+    fn next_cursor(output: QueryOutput) -> Option<String> {
+        match output {
+            QueryOutput::Page { page, .. } => page.next_cursor,
+            _ => panic!("a limited read gives back a page"),
+        }
+    }
+
+    #[test]
+    fn page_continues_after_the_last_row() {
+        let rows = QueryOutput::Rows(vec![json!({"uid": 1}), json!({"uid": 2})]);
+
+        let output = into_page(rows, &limited_read("uid"), None).unwrap();
+
+        let cursor = Cursor::decode(&next_cursor(output).unwrap()).unwrap();
+        assert_eq!(cursor.values, vec![json!(1)]);
+    }
+
+    #[test]
+    fn page_without_the_sort_key_has_no_next_cursor() {
+        let rows = QueryOutput::Rows(vec![json!({"username": "a"}), json!({"username": "b"})]);
+
+        let output = into_page(rows, &limited_read("created_at"), None).unwrap();
+
+        assert_eq!(next_cursor(output), None);
+    }
+
+    #[test]
+    fn aggregate_ordered_by_an_aggregate_has_no_next_cursor() {
+        let mut query_builder = limited_read("count");
+        query_builder.action = serde_json::from_value(json!({
+            "Aggregate": {
+                "aggregates": [{"function": "Count", "column": null, "alias": null}],
+                "group_by": ["email_verified"]
+            }
+        }))
+        .unwrap();
+        let rows = QueryOutput::Rows(vec![json!({"count": 1}), json!({"count": 2})]);
+
+        let output = into_page(rows, &query_builder, None).unwrap();
+
+        assert_eq!(next_cursor(output), None);
+    }
+}
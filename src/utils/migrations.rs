@@ -0,0 +1,396 @@
+use crate::db::TableModel;
+use crate::models::migrations::{
+    AppliedMigration, Migration, MigrationFn, MigrationRunner, MigrationStep,
+};
+use crate::utils::query_builder::{check_model_declares_columns, quote_identifier};
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+use sqlx::{Connection, PgConnection, PgPool, Row};
+
+// key of the advisory lock that keeps two runners from migrating at the same time
+const MIGRATION_LOCK: i64 = 0x6163_6964_345f_6d67;
+
+// CREATE TABLE statement generated from the columns of a TableModel
+// IF NOT EXISTS lets the migration adopt tables that were already set up by hand
+// meant for writing new migrations: paste its output into the migration as literal sql,
+// the model changes later on but the sql of an applied migration must not (see checksum)
+pub fn create_table_sql<T: TableModel>() -> Result<String> {
+    let columns = T::columns();
+    check_model_declares_columns(&columns, T::table_name())?;
+
+    let mut definitions: Vec<String> = columns
+        .iter()
+        .map(|column| {
+            format!(
+                "{} {}{}",
+                quote_identifier(column.name),
+                column.data_type,
+                if column.nullable { "" } else { " NOT NULL" }
+            )
+        })
+        .collect();
+
+    let primary_key: Vec<String> = columns
+        .iter()
+        .filter(|column| column.primary_key)
+        .map(|column| quote_identifier(column.name))
+        .collect();
+    if !primary_key.is_empty() {
+        definitions.push(format!("PRIMARY KEY ({})", primary_key.join(", ")));
+    }
+
+    Ok(format!(
+        "CREATE TABLE IF NOT EXISTS {} ({})",
+        quote_identifier(T::table_name()),
+        definitions.join(", ")
+    ))
+}
+
+pub fn drop_table_sql<T: TableModel>() -> String {
+    format!("DROP TABLE IF EXISTS {}", quote_identifier(T::table_name()))
+}
+
+// the tables this crate itself works with
+// the sql is frozen, it has to stay exactly as it was applied
+pub fn crate_migrations() -> Vec<Migration> {
+    vec![
+        Migration::sql(
+            1,
+            "create_users",
+            r#"CREATE TABLE IF NOT EXISTS "users" ("uid" bigint NOT NULL, "email" text NOT NULL, "owner" boolean NOT NULL, "email_verified" boolean NOT NULL, "username" text NOT NULL, PRIMARY KEY ("uid"))"#,
+        )
+        .with_down(MigrationStep::Sql(String::from(
+            r#"DROP TABLE IF EXISTS "users""#,
+        ))),
+        Migration::sql(
+            2,
+            "create_auth_users",
+            r#"CREATE TABLE IF NOT EXISTS "auth_users" ("uid" bigint NOT NULL, "email" text NOT NULL, "email_verified" boolean NOT NULL, "username" text NOT NULL, "password_hash" text NOT NULL, PRIMARY KEY ("uid"))"#,
+        )
+        .with_down(MigrationStep::Sql(String::from(
+            r#"DROP TABLE IF EXISTS "auth_users""#,
+        ))),
+        Migration::sql(
+            3,
+            "create_auth_tokens",
+            r#"CREATE TABLE IF NOT EXISTS "auth_tokens" ("jti" text NOT NULL, "uid" bigint NOT NULL, "expires_at" bigint NOT NULL, PRIMARY KEY ("jti"))"#,
+        )
+        .with_down(MigrationStep::Sql(String::from(
+            r#"DROP TABLE IF EXISTS "auth_tokens""#,
+        ))),
+        // users tables that were set up by hand before soft delete existed
+        Migration::sql(
+            4,
+            "add_users_deleted_at",
+            r#"ALTER TABLE "users" ADD COLUMN IF NOT EXISTS "deleted_at" timestamptz"#,
+        )
+        .with_down(MigrationStep::Sql(String::from(
+            r#"ALTER TABLE "users" DROP COLUMN IF EXISTS "deleted_at""#,
+        ))),
+    ]
+}
+
+impl MigrationStep {
+    async fn run(&self, conn: &mut PgConnection) -> Result<()> {
+        match self {
+            MigrationStep::Sql(sql) => {
+                // raw_sql allows several statements in one migration
+                sqlx::raw_sql(sql).execute(&mut *conn).await?;
+                Ok(())
+            }
+            MigrationStep::Rust(migration_fn) => migration_fn(conn).await,
+        }
+    }
+}
+
+impl Migration {
+    pub fn sql(version: i64, name: &str, up: &str) -> Self {
+        Self {
+            version,
+            name: name.to_string(),
+            up: MigrationStep::Sql(up.to_string()),
+            down: None,
+        }
+    }
+
+    pub fn rust(version: i64, name: &str, up: MigrationFn) -> Self {
+        Self {
+            version,
+            name: name.to_string(),
+            up: MigrationStep::Rust(up),
+            down: None,
+        }
+    }
+
+    pub fn with_down(mut self, down: MigrationStep) -> Self {
+        self.down = Some(down);
+        self
+    }
+
+    // sha256 of what the migration does when it is applied
+    // the code of rust migrations cannot be hashed, so only their name is
+    // sql built from a model at runtime would change with the model, so keep it literal
+    pub fn checksum(&self) -> String {
+        let source = match &self.up {
+            MigrationStep::Sql(sql) => format!("sql:{}", sql),
+            MigrationStep::Rust(_) => format!("rust:{}", self.name),
+        };
+
+        Sha256::digest(source.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+impl MigrationRunner {
+    pub fn new(mut migrations: Vec<Migration>) -> Self {
+        migrations.sort_by_key(|migration| migration.version);
+        Self {
+            migrations,
+            table: String::from("_migrations"),
+        }
+    }
+
+    pub fn with_table(mut self, table: &str) -> Self {
+        self.table = table.to_string();
+        self
+    }
+
+    // applies every migration that was not applied yet, in order of their version
+    // each migration runs in its own transaction together with its tracking row
+    // returns the versions that got applied
+    pub async fn migrate(&self, pool: &PgPool) -> Result<Vec<i64>> {
+        let mut conn = locked_connection(pool).await?;
+
+        let result = self.migrate_on(&mut conn).await;
+        // release the lock even if the migration failed, but its error is the one that counts
+        let unlock_result = unlock(&mut conn).await;
+        let versions = result?;
+        unlock_result?;
+
+        Ok(versions)
+    }
+
+    // rolls back every applied migration with a version above target_version, newest first
+    // returns the versions that got rolled back
+    pub async fn rollback(&self, pool: &PgPool, target_version: i64) -> Result<Vec<i64>> {
+        let mut conn = locked_connection(pool).await?;
+
+        let result = self.rollback_on(&mut conn, target_version).await;
+        let unlock_result = unlock(&mut conn).await;
+        let versions = result?;
+        unlock_result?;
+
+        Ok(versions)
+    }
+
+    // the migrations recorded in the tracking table, ordered by version
+    pub async fn applied(&self, conn: &mut PgConnection) -> Result<Vec<AppliedMigration>> {
+        self.ensure_table(conn).await?;
+
+        let rows = sqlx::query(&format!(
+            "SELECT version, name, checksum FROM {} ORDER BY version",
+            quote_identifier(&self.table)
+        ))
+        .fetch_all(&mut *conn)
+        .await?;
+
+        rows.iter()
+            .map(|row| {
+                Ok(AppliedMigration {
+                    version: row.try_get("version")?,
+                    name: row.try_get("name")?,
+                    checksum: row.try_get("checksum")?,
+                })
+            })
+            .collect()
+    }
+
+    // makes sure every applied migration still exists and was not changed since
+    pub async fn verify(&self, conn: &mut PgConnection) -> Result<Vec<AppliedMigration>> {
+        self.check_versions()?;
+
+        let applied = self.applied(conn).await?;
+
+        for applied_migration in &applied {
+            let migration = self.get(applied_migration.version).ok_or(anyhow!(
+                "Migration {} ({}) was applied but is not known to the runner",
+                applied_migration.version,
+                applied_migration.name
+            ))?;
+
+            if migration.checksum() != applied_migration.checksum {
+                return Err(anyhow!(
+                    "Migration {} ({}) was changed after it was applied, the checksums do not match",
+                    migration.version,
+                    migration.name
+                ));
+            }
+        }
+
+        Ok(applied)
+    }
+
+    fn get(&self, version: i64) -> Option<&Migration> {
+        self.migrations
+            .iter()
+            .find(|migration| migration.version == version)
+    }
+
+    fn check_versions(&self) -> Result<()> {
+        // the migrations are sorted, so duplicates sit next to each other
+        for pair in self.migrations.windows(2) {
+            if pair[0].version == pair[1].version {
+                return Err(anyhow!(
+                    "Migrations {} and {} share the version {}",
+                    pair[0].name,
+                    pair[1].name,
+                    pair[0].version
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    async fn ensure_table(&self, conn: &mut PgConnection) -> Result<()> {
+        sqlx::query(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (\
+             version bigint PRIMARY KEY, \
+             name text NOT NULL, \
+             checksum text NOT NULL, \
+             applied_at timestamptz NOT NULL DEFAULT now())",
+            quote_identifier(&self.table)
+        ))
+        .execute(&mut *conn)
+        .await?;
+        Ok(())
+    }
+
+    async fn migrate_on(&self, conn: &mut PgConnection) -> Result<Vec<i64>> {
+        let applied = self.verify(conn).await?;
+        let last_applied = applied.last().map(|migration| migration.version);
+
+        let pending: Vec<&Migration> = self
+            .migrations
+            .iter()
+            .filter(|migration| {
+                !applied
+                    .iter()
+                    .any(|applied_migration| applied_migration.version == migration.version)
+            })
+            .collect();
+
+        if let (Some(first_pending), Some(last_applied)) = (pending.first(), last_applied) {
+            if first_pending.version < last_applied {
+                return Err(anyhow!(
+                    "Migration {} ({}) is older than the already applied migration {}",
+                    first_pending.version,
+                    first_pending.name,
+                    last_applied
+                ));
+            }
+        }
+
+        let mut versions = Vec::new();
+
+        for migration in pending {
+            let mut tx = conn.begin().await?;
+
+            migration.up.run(&mut tx).await.map_err(|e| {
+                anyhow!(
+                    "Migration {} ({}) failed: {}",
+                    migration.version,
+                    migration.name,
+                    e
+                )
+            })?;
+
+            sqlx::query(&format!(
+                "INSERT INTO {} (version, name, checksum) VALUES ($1, $2, $3)",
+                quote_identifier(&self.table)
+            ))
+            .bind(migration.version)
+            .bind(&migration.name)
+            .bind(migration.checksum())
+            .execute(&mut *tx)
+            .await?;
+
+            tx.commit().await?;
+            versions.push(migration.version);
+        }
+
+        Ok(versions)
+    }
+
+    async fn rollback_on(&self, conn: &mut PgConnection, target_version: i64) -> Result<Vec<i64>> {
+        let applied = self.verify(conn).await?;
+
+        let to_roll_back: Vec<&Migration> = applied
+            .iter()
+            .rev()
+            .filter(|applied_migration| applied_migration.version > target_version)
+            // verify made sure every applied migration is known
+            .map(|applied_migration| self.get(applied_migration.version).unwrap())
+            .collect();
+
+        // check all of them first so we dont stop halfway through
+        if let Some(migration) = to_roll_back.iter().find(|m| m.down.is_none()) {
+            return Err(anyhow!(
+                "Migration {} ({}) cannot be rolled back, it has no down step",
+                migration.version,
+                migration.name
+            ));
+        }
+
+        let mut versions = Vec::new();
+
+        for migration in to_roll_back {
+            let mut tx = conn.begin().await?;
+
+            // checked above
+            let down = migration.down.as_ref().unwrap();
+            down.run(&mut tx).await.map_err(|e| {
+                anyhow!(
+                    "Rolling back migration {} ({}) failed: {}",
+                    migration.version,
+                    migration.name,
+                    e
+                )
+            })?;
+
+            sqlx::query(&format!(
+                "DELETE FROM {} WHERE version = $1",
+                quote_identifier(&self.table)
+            ))
+            .bind(migration.version)
+            .execute(&mut *tx)
+            .await?;
+
+            tx.commit().await?;
+            versions.push(migration.version);
+        }
+
+        Ok(versions)
+    }
+}
+
+// the advisory lock belongs to the session, so the connection is detached from the pool:
+// if the future gets dropped before unlock, the connection is closed instead of going back
+// to the pool still holding the lock, and closing it releases the lock
+async fn locked_connection(pool: &PgPool) -> Result<PgConnection> {
+    let mut conn = pool.acquire().await?.detach();
+    sqlx::query("SELECT pg_advisory_lock($1)")
+        .bind(MIGRATION_LOCK)
+        .execute(&mut conn)
+        .await?;
+    Ok(conn)
+}
+
+async fn unlock(conn: &mut PgConnection) -> Result<()> {
+    sqlx::query("SELECT pg_advisory_unlock($1)")
+        .bind(MIGRATION_LOCK)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
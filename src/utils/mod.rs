@@ -1,9 +1,15 @@
+pub mod column_type;
+pub mod cursor;
+pub mod database_error;
 pub mod deserializer;
 pub mod email_client;
+pub mod executor;
 pub mod hasher;
 pub mod jwt;
+pub mod migrations;
 pub mod query_builder;
 pub mod redis;
+pub mod schema;
 pub mod timer;
 pub mod token_handler;
 pub mod totp;
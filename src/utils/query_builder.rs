@@ -1,9 +1,16 @@
+use crate::db::{ColumnDef, ModelEntry, SOFT_DELETE_COLUMN};
 use crate::models::db::{
-    BuildQuery, BulkValues, DatabaseAction, DeleteAction, Filters, OrderDirection, QueryBuilder,
-    TableColumns, Values, WhereClause,
+    Aggregate, AggregateExpr, AggregateFunction, BuildQuery, BulkValues, ColumnType, Comparison,
+    Condition, ConflictAction, Cursor, DatabaseAction, DatabaseError, DatabaseRequest,
+    DeleteAction, Filters, JoinKind, JsonUpdate, NullsOrder, OnConflict, OrderBy, OrderDirection,
+    QueryBuilder, Search, TableColumns, Values, WhereClause, WhereNode,
 };
 use anyhow::{anyhow, Result};
 use serde_json::Value;
+use std::collections::HashMap;
+
+// postgres refuses statements with more bind parameters than this
+pub const MAX_BIND_PARAMS: usize = 65535;
 
 impl QueryBuilder {
     pub fn from(query_builder: QueryBuilder) -> Self {
@@ -24,22 +31,49 @@ impl QueryBuilder {
             bulk_values,
             values,
             filters,
+            json_updates: None,
             table_columns,
+            columns: None,
+            returning: None,
+            joins: None,
+            model_registry: None,
+            allowed_tables: None,
+            confirm_destructive: false,
+            include_deleted: false,
+            owner: None,
+            limits: None,
+            fetch_has_more: false,
             bind_params: Vec::new(),
         }
     }
 
+    // takes everything the request describes, the table columns come from the caller
+    pub fn from_request(request: DatabaseRequest, table_columns: Option<TableColumns>) -> Self {
+        Self {
+            table: request.table,
+            action: request.action,
+            filters: request.filters,
+            bulk_values: request.bulk_values,
+            values: request.values,
+            json_updates: request.json_updates,
+            table_columns,
+            columns: request.columns,
+            returning: request.returning,
+            joins: request.joins,
+            confirm_destructive: request.confirm_destructive,
+            include_deleted: request.include_deleted,
+            owner: request.owner,
+            ..Default::default()
+        }
+    }
+
     pub fn build_query(&mut self) -> Result<BuildQuery> {
-        /*
-        ==========================================
-        = DANGER: POTENTIAL SQL INJECTION RISK! =
-        ==========================================
-        */
-        // if you are directly embedding table names in queries,
-        // this can make your code VULNERABLE TO SQL INJECTION ATTEMPTS.
-        // if you use the DatabaseRequest::validate() function
-        // and provide the table argument only if the validate function passed,
-        // you should have nothing to worry about.
+        // every identifier that ends up in the query is quoted,
+        // and the table has to be registered or allowed if a registry or allowlist was supplied
+        // so this does not depend on DatabaseRequest::validate() being called first
+        self.check_table()?;
+        self.apply_owner()?;
+
         let mut query: String;
 
         let mut bind_index = 1;
@@ -47,7 +81,24 @@ impl QueryBuilder {
         // clone right here to defeat the borrow checker
         // (performance cost is acceptable)
         match &self.action.clone() {
-            DatabaseAction::Retrieve => query = format!("SELECT * FROM {}", self.table),
+            DatabaseAction::Retrieve => {
+                query = if self.joins.is_some() {
+                    self.build_join_query(&mut bind_index)?
+                } else {
+                    format!(
+                        "SELECT {} FROM {}",
+                        self.build_select()?,
+                        quote_identifier(&self.table)
+                    )
+                }
+            }
+            DatabaseAction::Aggregate(aggregate) => {
+                query = format!(
+                    "SELECT {} FROM {}",
+                    self.build_aggregate_select(aggregate)?,
+                    quote_identifier(&self.table)
+                )
+            }
             DatabaseAction::Update => {
                 query = self.build_update_set(&mut bind_index)?;
             }
@@ -57,7 +108,23 @@ impl QueryBuilder {
             DatabaseAction::Insert => {
                 query = self.build_insert_query(&mut bind_index)?;
             }
+            DatabaseAction::Upsert(on_conflict) => {
+                query = self.build_insert_query(&mut bind_index)?;
+                query.push_str(&self.build_on_conflict(on_conflict)?);
+            }
+            DatabaseAction::Transaction(_) => {
+                // every request of a transaction is built on its own
+                return Err(anyhow!(
+                    "Transactions cannot be built into a single query, use the QueryExecutor"
+                ));
+            }
             DatabaseAction::Delete(action) => {
+                if self.returning.is_some()
+                    && (*action == DeleteAction::DeleteTable || self.truncates(action))
+                {
+                    // neither DROP TABLE nor TRUNCATE TABLE can return rows
+                    return Err(anyhow!("Returning requires a where clause on delete"));
+                }
                 query = self.build_delete_query(action)?;
                 // dereference to compare
                 // if action equals DeleteTable we may want to exit early since a DROP TABLE does not support WHERE clauses or any other filters
@@ -68,64 +135,687 @@ impl QueryBuilder {
         }
 
         // putting it all together
+        let where_columns = self.where_columns()?;
+        let mut where_clause_sql = String::new();
         if let Some(filters) = &self.filters {
-            let (where_clause_sql, bind_values) = filters.build_where_caluse(&mut bind_index)?;
-            query.push_str(&where_clause_sql);
+            let (filters_sql, bind_values) =
+                filters.build_typed_where_clause(&mut bind_index, where_columns.as_ref())?;
+            where_clause_sql = filters_sql;
             self.bind_params.extend(bind_values);
+        }
+
+        if let Some(condition) = self.soft_delete_condition() {
+            // every where clause renders so that another AND can follow it
+            if where_clause_sql.is_empty() {
+                where_clause_sql = format!(" WHERE {}", condition);
+            } else {
+                where_clause_sql.push_str(&format!(" AND {}", condition));
+            }
+        }
+        if let Some((condition, bind_value)) = self.table_owner_condition(&mut bind_index)? {
+            if where_clause_sql.is_empty() {
+                where_clause_sql = format!(" WHERE {}", condition);
+            } else {
+                where_clause_sql.push_str(&format!(" AND {}", condition));
+            }
+            self.bind_params.push(bind_value);
+        }
+        if let Some(filters) = &self.filters {
+            if filters.cursor.is_some() && !self.action.is_read() {
+                return Err(anyhow!("A cursor is only supported by reading actions"));
+            }
+            if filters.cursor.is_some() && !self.cursor_keys_grouped() {
+                return Err(anyhow!(
+                    "A cursor on an aggregate requires every order_by column to be a group_by column"
+                ));
+            }
+            if let Some((condition, bind_values)) =
+                filters.build_cursor_condition(&mut bind_index, where_columns.as_ref())?
+            {
+                if where_clause_sql.is_empty() {
+                    where_clause_sql = format!(" WHERE {}", condition);
+                } else {
+                    where_clause_sql.push_str(&format!(" AND {}", condition));
+                }
+                self.bind_params.extend(bind_values);
+            }
+        }
+        query.push_str(&where_clause_sql);
+
+        // GROUP BY and HAVING have to sit between the WHERE and the ORDER BY
+        if let DatabaseAction::Aggregate(aggregate) = &self.action.clone() {
+            query.push_str(&self.build_group_by(aggregate, &mut bind_index)?);
+        }
 
-            // only call those function on DatabaseAction::Retrieve
-            if self.action == DatabaseAction::Retrieve {
-                let order_by_sql = filters.build_order_by()?;
+        // only reading actions get ORDER BY, LIMIT and OFFSET
+        if self.action.is_read() {
+            if let Some(filters) = &self.filters {
+                let (order_by_sql, bind_values) =
+                    filters.build_typed_order_by(&mut bind_index, where_columns.as_ref())?;
                 query.push_str(&order_by_sql);
+                self.bind_params.extend(bind_values);
+            }
 
-                let limit_sql = filters.build_limit()?;
-                query.push_str(&limit_sql);
+            if let Some(limit) = self.effective_limit()? {
+                // the extra row only tells if there is more, it gets dropped again
+                let limit = u64::from(limit) + u64::from(self.fetch_has_more);
+                query.push_str(&format!(" LIMIT {}", limit));
+            }
 
-                let offset_sql = filters.build_offset()?;
-                query.push_str(&offset_sql);
+            if let Some(filters) = &self.filters {
+                query.push_str(&filters.build_offset()?);
             }
         }
 
+        if !self.action.is_read() {
+            query.push_str(&self.build_returning()?);
+        }
+
         Ok((query, self.bind_params.clone()))
     }
 
-    fn convert_value(&self, column: &str, value: &Value, expected_type: &str) -> Result<Value> {
-        match expected_type {
-            "bigint" => {
-                if let Some(s) = value.as_str() {
-                    s.parse::<i64>()
-                        .map(|v| Value::Number(v.into()))
-                        .map_err(|_| anyhow!("Failed to convert {} to bigint", s))
-                } else if value.is_i64() || value.is_u64() {
-                    Ok(Value::Number(value.as_i64().unwrap().into()))
-                } else {
-                    Err(anyhow!("Expected a string or number for bigint"))
+    fn check_table(&self) -> Result<()> {
+        Filters::sanitize_column_name(&self.table)
+            .map_err(|_| anyhow!("Invalid table name: {}", self.table))?;
+
+        if self.model_registry.is_none() && self.allowed_tables.is_none() {
+            return Ok(());
+        }
+
+        let registered = self
+            .model_registry
+            .as_ref()
+            .is_some_and(|registry| registry.get(&self.table).is_some());
+        let allowed = self
+            .allowed_tables
+            .as_ref()
+            .is_some_and(|tables| tables.contains(&self.table));
+
+        if !registered && !allowed {
+            return Err(DatabaseError::permission_denied(format!(
+                "Table {} is not allowed",
+                self.table
+            ))
+            .into());
+        }
+
+        Ok(())
+    }
+
+    // the sort keys of a cursor end up in the where clause, which cannot
+    // refer to aggregated values, only to the columns the aggregate is grouped by
+    pub(crate) fn cursor_keys_grouped(&self) -> bool {
+        let DatabaseAction::Aggregate(aggregate) = &self.action else {
+            return true;
+        };
+
+        self.filters
+            .iter()
+            .flat_map(|filters| filters.order_by.iter().flatten())
+            .all(|key| aggregate.group_by.contains(&key.column))
+    }
+
+    // the limit of the filters held to the limits of the table
+    pub fn effective_limit(&self) -> Result<Option<u32>> {
+        let requested = self.filters.as_ref().and_then(|filters| filters.limit);
+
+        match (self.limits, requested) {
+            (Some(limits), Some(limit)) if limit > limits.max_limit => Err(anyhow!(
+                "Limit {} exceeds the maximum of {} rows for table {}",
+                limit,
+                limits.max_limit,
+                self.table
+            )),
+            (Some(limits), None) => Ok(Some(limits.default_limit)),
+            (_, requested) => Ok(requested),
+        }
+    }
+
+    fn is_soft_delete(&self) -> bool {
+        self.model_registry
+            .as_ref()
+            .and_then(|registry| registry.get(&self.table))
+            .is_some_and(|entry| entry.soft_delete)
+    }
+
+    // keeps rows that are marked as deleted out of the query
+    fn soft_delete_condition(&self) -> Option<String> {
+        if !self.is_soft_delete() {
+            return None;
+        }
+
+        let skip_deleted = match &self.action {
+            DatabaseAction::Retrieve | DatabaseAction::Aggregate(_) | DatabaseAction::Update => {
+                !self.include_deleted
+            }
+            // deleting again would move deleted_at
+            DatabaseAction::Delete(DeleteAction::DeleteValue) => true,
+            _ => false,
+        };
+
+        skip_deleted.then(|| {
+            format!(
+                "{}.{} IS NULL",
+                quote_identifier(&self.table),
+                quote_identifier(SOFT_DELETE_COLUMN)
+            )
+        })
+    }
+
+    // whether the delete empties the whole table with a TRUNCATE
+    // an owner-scoped delete always has the owner condition, so it never does
+    pub(crate) fn truncates(&self, action: &DeleteAction) -> bool {
+        let hard_delete = match action {
+            DeleteAction::DeleteTable => return false,
+            DeleteAction::DeleteValue => !self.is_soft_delete(),
+            DeleteAction::Purge => true,
+        };
+        hard_delete && !query_has_where(&self.filters) && self.owner_column().is_none()
+    }
+
+    // the owner column of the table, only if the request runs on behalf of an owner
+    pub fn owner_column(&self) -> Option<&'static str> {
+        self.owner.as_ref()?;
+        self.model_registry
+            .as_ref()
+            .and_then(|registry| registry.get(&self.table))
+            .and_then(|entry| entry.owner_column)
+    }
+
+    // forces the owner into everything that gets written to an owner-scoped table
+    fn apply_owner(&mut self) -> Result<()> {
+        // without a registry there is no owner column, the owner would be ignored silently
+        if self.owner.is_some() && self.model_registry.is_none() {
+            return Err(anyhow!("An owner requires a model registry"));
+        }
+
+        let (Some(owner_column), Some(owner)) = (self.owner_column(), self.owner.clone()) else {
+            return Ok(());
+        };
+
+        match &self.action {
+            DatabaseAction::Insert | DatabaseAction::Upsert(_) => {
+                if let Some(values) = self.values.as_mut() {
+                    values.insert(owner_column.to_string(), owner);
                 }
             }
-            "text" => {
-                if value.is_string() {
-                    Ok(value.clone())
-                } else {
-                    Err(anyhow!("Expected a string for text column {}", column))
+            DatabaseAction::BulkInsert => {
+                if let Some(bulk_values) = self.bulk_values.as_mut() {
+                    for row in bulk_values.iter_mut() {
+                        row.insert(owner_column.to_string(), owner.clone());
+                    }
                 }
             }
-            "boolean" => {
-                if let Some(b) = value.as_bool() {
-                    Ok(Value::Bool(b))
-                } else {
-                    Err(anyhow!("Expected a boolean for column {}", column))
+            // this would hand the rows over to another owner
+            DatabaseAction::Update
+                if self
+                    .values
+                    .as_ref()
+                    .is_some_and(|values| values.contains_key(owner_column)) =>
+            {
+                return Err(anyhow!(
+                    "Column {} of owner-scoped table {} cannot be updated",
+                    owner_column,
+                    self.table
+                ));
+            }
+            DatabaseAction::Delete(DeleteAction::DeleteTable) => {
+                return Err(anyhow!(
+                    "Table {} is owner-scoped and cannot be dropped on behalf of an owner",
+                    self.table
+                ));
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    // keeps the rows of other owners out of reads, updates and deletes
+    fn table_owner_condition(&self, bind_index: &mut usize) -> Result<Option<(String, Value)>> {
+        let scoped = match &self.action {
+            DatabaseAction::Retrieve | DatabaseAction::Aggregate(_) | DatabaseAction::Update => {
+                true
+            }
+            DatabaseAction::Delete(action) => *action != DeleteAction::DeleteTable,
+            _ => false,
+        };
+        if !scoped {
+            return Ok(None);
+        }
+
+        match self
+            .model_registry
+            .as_ref()
+            .and_then(|registry| registry.get(&self.table))
+        {
+            Some(entry) => self.owner_condition(&self.table, entry, bind_index),
+            None => Ok(None),
+        }
+    }
+
+    // "table"."owner_column" = $n, the owner converted to the type of the owner column
+    fn owner_condition(
+        &self,
+        table: &str,
+        entry: &ModelEntry,
+        bind_index: &mut usize,
+    ) -> Result<Option<(String, Value)>> {
+        let (Some(owner), Some(owner_column)) = (&self.owner, entry.owner_column) else {
+            return Ok(None);
+        };
+
+        check_model_declares_columns(&entry.columns, table)?;
+        let column_type = entry
+            .columns
+            .iter()
+            .find(|column| column.name == owner_column)
+            .ok_or(anyhow!(
+                "Owner column {} does not exist in table {}",
+                owner_column,
+                table
+            ))?
+            .column_type();
+
+        let bind_value = column_type.convert(owner_column, owner)?;
+        let condition = format!(
+            "{}.{} = {}",
+            quote_identifier(table),
+            quote_identifier(owner_column),
+            column_type.placeholder(*bind_index)?
+        );
+        *bind_index += 1;
+
+        Ok(Some((condition, bind_value)))
+    }
+
+    // the columns the where clause values get checked against
+    // joined queries also get the columns of every table in them from the registry,
+    // qualified as "table.column" so filters on joined tables are typed as well
+    fn where_columns(&self) -> Result<Option<TableColumns>> {
+        let joins = match &self.joins {
+            Some(joins) => joins,
+            None => return Ok(self.table_columns.clone()),
+        };
+        let registry = self
+            .model_registry
+            .as_ref()
+            .ok_or(anyhow!("Joins require a model registry"))?;
+
+        let mut where_columns = self.table_columns.clone().unwrap_or_default();
+        let tables = std::iter::once(&self.table).chain(joins.iter().map(|join| &join.table));
+
+        for table in tables {
+            let entry = registry
+                .get(table)
+                .ok_or(anyhow!("Table {} is not registered", table))?;
+
+            for column in &entry.columns {
+                if *table == self.table {
+                    where_columns
+                        .entry(column.name.to_string())
+                        .or_insert_with(|| column.column_type());
+                }
+                where_columns.insert(format!("{}.{}", table, column.name), column.column_type());
+            }
+        }
+
+        Ok(Some(where_columns))
+    }
+
+    fn build_select(&self) -> Result<String> {
+        if let Some(columns) = &self.columns {
+            return self.build_column_list(columns);
+        }
+
+        let entry = self
+            .model_registry
+            .as_ref()
+            .and_then(|registry| registry.get(&self.table));
+        match entry {
+            // spell out the columns of the model so sensitive ones stay out
+            Some(entry) if !entry.columns.is_empty() => Ok(entry
+                .default_columns()
+                .map(|column| quote_identifier(column.name))
+                .collect::<Vec<String>>()
+                .join(", ")),
+            _ => Ok(String::from("*")),
+        }
+    }
+
+    fn build_returning(&self) -> Result<String> {
+        if let Some(returning) = &self.returning {
+            Ok(format!(" RETURNING {}", self.build_column_list(returning)?))
+        } else {
+            Ok(String::new()) // no RETURNING clause if not specified
+        }
+    }
+
+    fn build_join_query(&mut self, bind_index: &mut usize) -> Result<String> {
+        let joins = self.joins.as_ref().ok_or(anyhow!("No joins provided"))?;
+        let registry = self
+            .model_registry
+            .as_ref()
+            .ok_or(anyhow!("Joins require a model registry"))?;
+
+        if joins.is_empty() {
+            return Err(anyhow!("Joins cannot be empty"));
+        }
+
+        let base_entry = registry
+            .get(&self.table)
+            .ok_or(anyhow!("Table {} is not registered", self.table))?;
+
+        // every table that is part of the query so far, the requested one comes first
+        let mut tables: Vec<(&str, &ModelEntry)> = vec![(&self.table, base_entry)];
+        let mut join_sql = String::new();
+        let mut bind_values = Vec::new();
+
+        for join in joins {
+            let joined_table = Filters::sanitize_column_name(&join.table)?;
+            if tables.iter().any(|(table, _)| *table == join.table) {
+                return Err(anyhow!("Table {} is joined more than once", join.table));
+            }
+
+            let entry = registry
+                .get(&join.table)
+                .ok_or(anyhow!("Table {} is not registered", join.table))?;
+
+            if join.on.is_empty() {
+                return Err(anyhow!(
+                    "Join on {} requires at least one column pair",
+                    join.table
+                ));
+            }
+
+            let mut conditions = Vec::new();
+            for on in &join.on {
+                let (left_table, left_column) = match on.left.split_once('.') {
+                    Some((table, column)) => (table, column),
+                    None => (self.table.as_str(), on.left.as_str()),
+                };
+                let left_entry = tables
+                    .iter()
+                    .find(|(table, _)| *table == left_table)
+                    .map(|(_, entry)| *entry)
+                    .ok_or(anyhow!("Table {} is not part of the query", left_table))?;
+
+                check_model_column(left_entry, left_table, left_column)?;
+                check_model_column(entry, &join.table, &on.right)?;
+
+                conditions.push(format!(
+                    "{}.{} = {}.{}",
+                    quote_identifier(left_table),
+                    quote_identifier(left_column),
+                    joined_table,
+                    quote_identifier(&on.right)
+                ));
+            }
+
+            // deleted rows and rows of other owners are kept out in the ON clause,
+            // so a LEFT JOIN still keeps the unmatched rows of the left side
+            if entry.soft_delete && !self.include_deleted {
+                conditions.push(format!(
+                    "{}.{} IS NULL",
+                    joined_table,
+                    quote_identifier(SOFT_DELETE_COLUMN)
+                ));
+            }
+            if let Some((condition, bind_value)) =
+                self.owner_condition(&join.table, entry, bind_index)?
+            {
+                conditions.push(condition);
+                bind_values.push(bind_value);
+            }
+
+            join_sql.push_str(&format!(
+                " {} {} ON {}",
+                join.kind.as_str(),
+                joined_table,
+                conditions.join(" AND ")
+            ));
+            tables.push((&join.table, entry));
+        }
+
+        // alias every column with its table so the keys of the combined rows stay unique
+        let selected: Vec<String> = match &self.columns {
+            Some(columns) => {
+                if columns.is_empty() {
+                    return Err(anyhow!("Columns cannot be empty"));
+                }
+                columns
+                    .iter()
+                    .map(|column| {
+                        let (table, column) = column.split_once('.').ok_or(anyhow!(
+                            "Column {} has to be qualified (table.column) when joining",
+                            column
+                        ))?;
+                        let entry = tables
+                            .iter()
+                            .find(|(name, _)| *name == table)
+                            .map(|(_, entry)| *entry)
+                            .ok_or(anyhow!("Table {} is not part of the query", table))?;
+                        check_model_column(entry, table, column)?;
+                        Ok(build_joined_column(table, column))
+                    })
+                    .collect::<Result<Vec<String>>>()?
+            }
+            None => tables
+                .iter()
+                .flat_map(|(table, entry)| {
+                    entry
+                        .default_columns()
+                        .map(move |column| build_joined_column(table, column.name))
+                })
+                .collect(),
+        };
+
+        let query = format!(
+            "SELECT {} FROM {}{}",
+            selected.join(", "),
+            quote_identifier(&self.table),
+            join_sql
+        );
+        self.bind_params.extend(bind_values);
+
+        Ok(query)
+    }
+
+    fn build_aggregate_select(&self, aggregate: &Aggregate) -> Result<String> {
+        if aggregate.aggregates.is_empty() {
+            return Err(anyhow!(
+                "Aggregate requires at least one aggregate function"
+            ));
+        }
+
+        let mut selected = Vec::new();
+
+        for column in &aggregate.group_by {
+            selected.push(self.check_aggregate_column(column)?);
+        }
+
+        for expr in &aggregate.aggregates {
+            let alias = match &expr.alias {
+                Some(alias) => Filters::sanitize_column_name(alias)?,
+                None => quote_identifier(&expr.default_alias()),
+            };
+            selected.push(format!("{} AS {}", self.build_aggregate_expr(expr)?, alias));
+        }
+
+        Ok(selected.join(", "))
+    }
+
+    fn build_group_by(&mut self, aggregate: &Aggregate, bind_index: &mut usize) -> Result<String> {
+        let mut query = String::new();
+
+        if !aggregate.group_by.is_empty() {
+            let columns = aggregate
+                .group_by
+                .iter()
+                .map(|column| self.check_aggregate_column(column))
+                .collect::<Result<Vec<String>>>()?;
+            query.push_str(&format!(" GROUP BY {}", columns.join(", ")));
+        }
+
+        if !aggregate.having.is_empty() {
+            let mut bind_values = Vec::new();
+            let mut conditions = Vec::new();
+
+            for having in &aggregate.having {
+                let expr = self.build_aggregate_expr(&having.aggregate)?;
+                conditions.push(build_comparison(
+                    &expr,
+                    &having.op,
+                    None,
+                    bind_index,
+                    &mut bind_values,
+                )?);
+            }
+
+            query.push_str(&format!(" HAVING {}", conditions.join(" AND ")));
+            self.bind_params.extend(bind_values);
+        }
+
+        Ok(query)
+    }
+
+    fn build_aggregate_expr(&self, expr: &AggregateExpr) -> Result<String> {
+        let function = expr.function.as_str();
+
+        let column = match &expr.column {
+            Some(column) => column,
+            None if expr.function == AggregateFunction::Count => {
+                return Ok(String::from("COUNT(*)"));
+            }
+            None => return Err(anyhow!("{} requires a column", function)),
+        };
+
+        let sanitized_column = self.check_aggregate_column(column)?;
+
+        if matches!(
+            expr.function,
+            AggregateFunction::Sum | AggregateFunction::Avg
+        ) {
+            // table_columns is checked by check_aggregate_column already
+            let expected_type = &self.table_columns.as_ref().unwrap()[column];
+            if !expected_type.is_numeric() {
+                return Err(anyhow!(
+                    "{} requires a numeric column, {} is {}",
+                    function,
+                    column,
+                    expected_type.data_type
+                ));
+            }
+        }
+
+        Ok(format!("{}({})", function, sanitized_column))
+    }
+
+    fn check_aggregate_column(&self, column: &str) -> Result<String> {
+        let table_columns = self
+            .table_columns
+            .as_ref()
+            .ok_or(anyhow!("No table columns provided"))?;
+
+        let sanitized_column = Filters::sanitize_column_name(column)?;
+        if !table_columns.contains_key(column) {
+            return Err(anyhow!(
+                "Column {} does not exist in table {}",
+                column,
+                self.table
+            ));
+        }
+
+        Ok(sanitized_column)
+    }
+
+    fn build_column_list(&self, columns: &[String]) -> Result<String> {
+        if columns.is_empty() {
+            return Err(anyhow!("Columns cannot be empty"));
+        }
+
+        let mut selected = Vec::new();
+
+        for column in columns {
+            let sanitized_column = Filters::sanitize_column_name(column)?;
+
+            // only check against the table columns if we got them
+            if let Some(table_columns) = &self.table_columns {
+                if !table_columns.contains_key(column) {
+                    return Err(anyhow!(
+                        "Column {} does not exist in table {}",
+                        column,
+                        self.table
+                    ));
                 }
             }
-            _ => Err(anyhow!("Unsupported column type: {}", expected_type)),
+
+            selected.push(sanitized_column);
+        }
+
+        Ok(selected.join(", "))
+    }
+
+    fn convert_value(
+        &self,
+        column: &str,
+        value: &Value,
+        expected_type: &ColumnType,
+    ) -> Result<Value> {
+        expected_type.convert(column, value)
+    }
+
+    fn build_bulk_insert_query(&mut self, bind_index: &mut usize) -> Result<String> {
+        let columns = self.bulk_insert_columns()?;
+        // checked by bulk_insert_columns already
+        let bulk_values = self.bulk_values.as_ref().unwrap();
+
+        if bulk_values.len() * columns.len() > MAX_BIND_PARAMS {
+            return Err(anyhow!(
+                "Bulk insert of {} rows needs more than {} bind parameters, use build_bulk_insert_queries",
+                bulk_values.len(),
+                MAX_BIND_PARAMS
+            ));
+        }
+
+        let (query, bind_params) =
+            self.build_bulk_insert_rows(&columns, bulk_values, 0, bind_index)?;
+        self.bind_params.extend(bind_params);
+
+        Ok(query)
+    }
+
+    // splits the rows into as many statements as needed to stay below MAX_BIND_PARAMS
+    // every statement counts its placeholders from $1 again and gets its own RETURNING
+    pub fn build_bulk_insert_queries(&mut self) -> Result<Vec<BuildQuery>> {
+        self.check_table()?;
+        self.apply_owner()?;
+
+        let columns = self.bulk_insert_columns()?;
+        let bulk_values = self.bulk_values.as_ref().unwrap();
+        let rows_per_query = MAX_BIND_PARAMS / columns.len();
+        let returning = self.build_returning()?;
+
+        let mut queries = Vec::new();
+
+        for (i, rows) in bulk_values.chunks(rows_per_query).enumerate() {
+            let mut bind_index = 1;
+            let (mut query, bind_params) =
+                self.build_bulk_insert_rows(&columns, rows, i * rows_per_query, &mut bind_index)?;
+            query.push_str(&returning);
+            queries.push((query, bind_params));
         }
+
+        Ok(queries)
     }
 
-    fn build_bulk_insert_query(&mut self, _bind_index: &mut usize) -> Result<String> {
+    // the columns of the first row, every other row has to have exactly the same ones
+    // they are checked against the table before anything gets split up by them
+    fn bulk_insert_columns(&self) -> Result<Vec<String>> {
         let bulk_values = self
             .bulk_values
             .as_ref()
             .ok_or(anyhow!("No bulk values provided for insert"))?;
-
         let table_columns = self
             .table_columns
             .as_ref()
@@ -135,45 +825,113 @@ impl QueryBuilder {
             return Err(anyhow!("Bulk values cannot be empty"));
         }
 
-        let columns: Vec<String> = bulk_values[0].keys().cloned().collect();
+        let mut columns: Vec<String> = bulk_values[0].keys().cloned().collect();
+        if columns.is_empty() {
+            return Err(anyhow!("Row 0 of the bulk values has no columns"));
+        }
+        columns.sort();
+
+        if let Some(column) = columns
+            .iter()
+            .find(|column| !table_columns.contains_key(*column))
+        {
+            return Err(anyhow!(
+                "Column {} does not exist in table {}",
+                column,
+                self.table
+            ));
+        }
+
+        let mut row_errors = Vec::new();
+
+        for (i, row) in bulk_values.iter().enumerate().skip(1) {
+            let missing: Vec<&str> = columns
+                .iter()
+                .filter(|column| !row.contains_key(*column))
+                .map(String::as_str)
+                .collect();
+            let mut extra: Vec<&str> = row
+                .keys()
+                .filter(|column| !bulk_values[0].contains_key(*column))
+                .map(String::as_str)
+                .collect();
+            extra.sort();
+
+            if !missing.is_empty() {
+                row_errors.push(format!(
+                    "row {} is missing column(s) {}",
+                    i,
+                    missing.join(", ")
+                ));
+            }
+            if !extra.is_empty() {
+                row_errors.push(format!(
+                    "row {} has column(s) {} which row 0 does not have",
+                    i,
+                    extra.join(", ")
+                ));
+            }
+        }
+
+        if !row_errors.is_empty() {
+            return Err(anyhow!(
+                "Every row of a bulk insert needs the same columns: {}",
+                row_errors.join("; ")
+            ));
+        }
+
+        Ok(columns)
+    }
+
+    // first_row is only used to point at the right row in errors
+    fn build_bulk_insert_rows(
+        &self,
+        columns: &[String],
+        rows: &[Values],
+        first_row: usize,
+        bind_index: &mut usize,
+    ) -> Result<BuildQuery> {
+        // checked by bulk_insert_columns already
+        let table_columns = self.table_columns.as_ref().unwrap();
 
         let mut value_sets: Vec<String> = Vec::new();
+        let mut bind_params: Vec<Value> = Vec::new();
 
-        for row in bulk_values {
+        for (i, row) in rows.iter().enumerate() {
             let mut row_placeholders: Vec<String> = Vec::new();
-            let mut row_bind_params: Vec<serde_json::Value> = Vec::new();
 
-            for column in &columns {
-                let value = row
-                    .get(column)
-                    .ok_or(anyhow!("Missing value for column {} in row", column))?;
+            for column in columns {
+                // every row and the table have the columns, bulk_insert_columns checked that
+                let value = &row[column];
+                let expected_type = &table_columns[column];
 
-                let expected_type = table_columns.get(column).ok_or(anyhow!(
-                    "Column {} does not exist in table {}",
-                    column,
-                    self.table
-                ))?;
-
-                let converted_value = self.convert_value(column, value, expected_type)?;
-                row_bind_params.push(converted_value);
+                let converted_value =
+                    self.convert_value(column, value, expected_type)
+                        .map_err(|e| {
+                            DatabaseError::from_anyhow(&e)
+                                .prefixed(&format!("row {}", first_row + i))
+                        })?;
+                bind_params.push(converted_value);
 
-                row_placeholders.push(format!("${}", _bind_index));
-                *_bind_index += 1;
+                row_placeholders.push(expected_type.placeholder(*bind_index)?);
+                *bind_index += 1;
             }
 
             value_sets.push(format!("({})", row_placeholders.join(", ")));
-
-            self.bind_params.extend(row_bind_params);
         }
 
         let query = format!(
             "INSERT INTO {} ({}) VALUES {}",
-            self.table,
-            columns.join(", "),
+            quote_identifier(&self.table),
+            columns
+                .iter()
+                .map(|column| Filters::sanitize_column_name(column))
+                .collect::<Result<Vec<String>>>()?
+                .join(", "),
             value_sets.join(", ")
         );
 
-        Ok(query)
+        Ok((query, bind_params))
     }
 
     fn build_insert_query(&mut self, _bind_index: &mut usize) -> Result<String> {
@@ -187,7 +945,7 @@ impl QueryBuilder {
             .ok_or(anyhow!("No table columns provided"))?;
 
         let columns: Vec<String> = values.keys().cloned().collect();
-        let placeholders: Vec<String> = (1..=columns.len()).map(|i| format!("${}", i)).collect();
+        let mut placeholders: Vec<String> = Vec::new();
 
         for column in &columns {
             let value = values.get(column).unwrap();
@@ -199,128 +957,516 @@ impl QueryBuilder {
 
             let converted_value = self.convert_value(column, value, expected_type)?;
             self.bind_params.push(converted_value);
+
+            placeholders.push(expected_type.placeholder(*_bind_index)?);
+            *_bind_index += 1;
         }
 
         Ok(format!(
             "INSERT INTO {} ({}) VALUES ({})",
-            self.table,
-            columns.join(", "),
+            quote_identifier(&self.table),
+            columns
+                .iter()
+                .map(|column| Filters::sanitize_column_name(column))
+                .collect::<Result<Vec<String>>>()?
+                .join(", "),
             placeholders.join(", ")
         ))
     }
 
-    fn build_update_set(&mut self, bind_index: &mut usize) -> Result<String> {
+    fn build_on_conflict(&self, on_conflict: &OnConflict) -> Result<String> {
         let values = self
             .values
             .as_ref()
-            .ok_or(anyhow!("No values provided for update"))?;
+            .ok_or(anyhow!("No values provided for upsert"))?;
         let table_columns = self
             .table_columns
             .as_ref()
             .ok_or(anyhow!("No table columns provided"))?;
 
-        let mut set_clauses = Vec::new();
+        if on_conflict.target.is_empty() {
+            return Err(anyhow!(
+                "Upsert requires at least one conflict target column"
+            ));
+        }
 
-        for (column, value) in values {
+        let mut target = Vec::new();
+        for column in &on_conflict.target {
             let sanitized_column = Filters::sanitize_column_name(column)?;
-            let expected_type = table_columns.get(column).ok_or(anyhow!(
-                "Column {} does not exist in table {}",
-                column,
-                self.table
-            ))?;
+            if !table_columns.contains_key(column) {
+                return Err(anyhow!(
+                    "Column {} does not exist in table {}",
+                    column,
+                    self.table
+                ));
+            }
+            target.push(sanitized_column);
+        }
+
+        match &on_conflict.action {
+            ConflictAction::DoNothing => {
+                Ok(format!(" ON CONFLICT ({}) DO NOTHING", target.join(", ")))
+            }
+            ConflictAction::DoUpdate(columns) => {
+                if columns.is_empty() {
+                    return Err(anyhow!("Upsert requires at least one column to update"));
+                }
+
+                let mut set_clauses = Vec::new();
+                for column in columns {
+                    let sanitized_column = Filters::sanitize_column_name(column)?;
+                    // EXCLUDED only holds the columns we actually tried to insert
+                    if !values.contains_key(column) {
+                        return Err(anyhow!(
+                            "Column {} has to be part of the values to be updated",
+                            column
+                        ));
+                    }
+                    set_clauses.push(format!(
+                        "{} = EXCLUDED.{}",
+                        sanitized_column, sanitized_column
+                    ));
+                }
+
+                let mut query = format!(
+                    " ON CONFLICT ({}) DO UPDATE SET {}",
+                    target.join(", "),
+                    set_clauses.join(", ")
+                );
+                // the conflicting row may belong to someone else, only update it if it does not
+                if let Some(owner_column) = self.owner_column() {
+                    let owner_column = quote_identifier(owner_column);
+                    query.push_str(&format!(
+                        " WHERE {}.{} = EXCLUDED.{}",
+                        quote_identifier(&self.table),
+                        owner_column,
+                        owner_column
+                    ));
+                }
+
+                Ok(query)
+            }
+        }
+    }
+
+    fn build_update_set(&mut self, bind_index: &mut usize) -> Result<String> {
+        if self.values.is_none() && self.json_updates.is_none() {
+            return Err(anyhow!("No values provided for update"));
+        }
+        let empty_values = Values::new();
+        let values = self.values.as_ref().unwrap_or(&empty_values);
+        let table_columns = self
+            .table_columns
+            .as_ref()
+            .ok_or(anyhow!("No table columns provided"))?;
+
+        let mut set_clauses = Vec::new();
+
+        for (column, value) in values {
+            let sanitized_column = Filters::sanitize_column_name(column)?;
+            let expected_type = table_columns.get(column).ok_or(anyhow!(
+                "Column {} does not exist in table {}",
+                column,
+                self.table
+            ))?;
 
             let converted_value = self.convert_value(column, value, expected_type)?;
             self.bind_params.push(converted_value);
 
-            set_clauses.push(format!("{} = ${}", sanitized_column, bind_index));
+            set_clauses.push(format!(
+                "{} = {}",
+                sanitized_column,
+                expected_type.placeholder(*bind_index)?
+            ));
             *bind_index += 1;
         }
 
+        if let Some(json_updates) = &self.json_updates {
+            let (json_set_clauses, bind_values) =
+                self.build_json_updates(json_updates, values, bind_index)?;
+            set_clauses.extend(json_set_clauses);
+            self.bind_params.extend(bind_values);
+        }
+
+        if set_clauses.is_empty() {
+            return Err(anyhow!("Update requires at least one value"));
+        }
+
         Ok(format!(
             "UPDATE {} SET {}",
-            self.table,
+            quote_identifier(&self.table),
             set_clauses.join(", ")
         ))
     }
 
+    // several updates of the same column get nested into each other,
+    // so every one of them applies to the result of the one before
+    fn build_json_updates(
+        &self,
+        json_updates: &[JsonUpdate],
+        values: &Values,
+        bind_index: &mut usize,
+    ) -> Result<(Vec<String>, Vec<Value>)> {
+        let table_columns = self
+            .table_columns
+            .as_ref()
+            .ok_or(anyhow!("No table columns provided"))?;
+
+        let path_type = ColumnType::from("text[]");
+        let mut bind_values = Vec::new();
+        // (column, expression) in the order the columns first showed up
+        let mut documents: Vec<(String, String)> = Vec::new();
+
+        for update in json_updates {
+            let sanitized_column = Filters::sanitize_column_name(&update.column)?;
+            let expected_type = table_columns.get(&update.column).ok_or(anyhow!(
+                "Column {} does not exist in table {}",
+                update.column,
+                self.table
+            ))?;
+
+            if !expected_type.is_jsonb() {
+                return Err(anyhow!(
+                    "json_updates require a jsonb column, {} is {}",
+                    update.column,
+                    expected_type.data_type
+                ));
+            }
+            if values.contains_key(&update.column) {
+                return Err(anyhow!(
+                    "Column {} cannot be set by values and json_updates at once",
+                    update.column
+                ));
+            }
+            if update.path.is_empty() {
+                return Err(anyhow!(
+                    "json_updates on column {} require a path",
+                    update.column
+                ));
+            }
+
+            bind_values.push(path_type.convert(&update.column, &serde_json::json!(update.path))?);
+            let path_placeholder = path_type.placeholder(*bind_index)?;
+            *bind_index += 1;
+
+            bind_values.push(Value::String(update.value.to_string()));
+            let value_placeholder = format!("${}::jsonb", *bind_index);
+            *bind_index += 1;
+
+            let position = match documents
+                .iter()
+                .position(|(column, _)| *column == sanitized_column)
+            {
+                Some(position) => position,
+                None => {
+                    // a NULL document would stay NULL, start from an empty object instead
+                    documents.push((
+                        sanitized_column.clone(),
+                        format!("coalesce({}, '{{}}'::jsonb)", sanitized_column),
+                    ));
+                    documents.len() - 1
+                }
+            };
+
+            let document = &mut documents[position].1;
+            *document = format!(
+                "jsonb_set({}, {}, {}, true)",
+                document, path_placeholder, value_placeholder
+            );
+        }
+
+        let set_clauses = documents
+            .into_iter()
+            .map(|(column, document)| format!("{} = {}", column, document))
+            .collect();
+
+        Ok((set_clauses, bind_values))
+    }
+
     fn build_delete_query(&mut self, del_action: &DeleteAction) -> Result<String> {
         match del_action {
-            DeleteAction::DeleteTable => Ok(format!("DROP TABLE {}", self.table)),
-            DeleteAction::DeleteValue => {
-                if let Some(_filters) = self.filters.as_ref().and_then(|f| f.where_clause.as_ref())
-                {
-                    Ok(format!("DELETE FROM {}", self.table))
+            DeleteAction::DeleteTable => {
+                if !self.confirm_destructive {
+                    return Err(anyhow!(
+                        "Dropping table {} requires confirm_destructive",
+                        self.table
+                    ));
+                }
+                Ok(format!("DROP TABLE {}", quote_identifier(&self.table)))
+            }
+            DeleteAction::DeleteValue | DeleteAction::Purge => {
+                // a TRUNCATE would empty the table, so only do this if it was asked for explicitly
+                // soft deletes and owner-scoped deletes without a where clause keep the other rows
+                if self.truncates(del_action) && !self.confirm_destructive {
+                    return Err(anyhow!(
+                        "Deleting without a where clause empties table {}, this requires confirm_destructive",
+                        self.table
+                    ));
+                }
+
+                if *del_action == DeleteAction::DeleteValue && self.is_soft_delete() {
+                    Ok(format!(
+                        "UPDATE {} SET {} = now()",
+                        quote_identifier(&self.table),
+                        quote_identifier(SOFT_DELETE_COLUMN)
+                    ))
+                } else if self.truncates(del_action) {
+                    Ok(format!("TRUNCATE TABLE {}", quote_identifier(&self.table)))
                 } else {
-                    // if there are no filters
-                    Ok(format!("TRUNCATE TABLE {}", self.table))
+                    Ok(format!("DELETE FROM {}", quote_identifier(&self.table)))
                 }
             }
         }
     }
 }
 
+fn query_has_where(filters: &Option<Filters>) -> bool {
+    filters
+        .as_ref()
+        .is_some_and(|f| f.where_clause.is_some() || f.search.is_some())
+}
+
+// wraps an identifier in double quotes so reserved words (order, user, ...) can be used as names
+// quotes inside of it get doubled, which is how postgres escapes them
+pub fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+// the quoted column of a joined table, aliased as "table.column"
+// so the keys of the combined rows stay unique
+fn build_joined_column(table: &str, column: &str) -> String {
+    format!(
+        "{}.{} AS {}",
+        quote_identifier(table),
+        quote_identifier(column),
+        quote_identifier(&format!("{}.{}", table, column))
+    )
+}
+
 impl Filters {
+    // checks the name and gives it back quoted, ready to be put into a query
     fn sanitize_column_name(column: &str) -> Result<String> {
-        let is_valid = column
-            .chars()
-            .all(|c| c.is_ascii_alphanumeric() || c == '_');
+        let is_valid = !column.is_empty()
+            && column
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_');
         if !is_valid {
             return Err(anyhow::anyhow!("Invalid column name: {}", column));
         }
-        Ok(column.to_string())
+        Ok(quote_identifier(column))
+    }
+
+    // like sanitize_column_name but also accepts a qualified "table.column"
+    // which is needed to tell columns apart once tables are joined
+    fn sanitize_column_ref(column: &str) -> Result<String> {
+        match column.split_once('.') {
+            Some((table, column)) => Ok(format!(
+                "{}.{}",
+                Self::sanitize_column_name(table)?,
+                Self::sanitize_column_name(column)?
+            )),
+            None => Self::sanitize_column_name(column),
+        }
     }
 
     pub fn build_where_caluse(&self, bind_index: &mut usize) -> Result<(String, Vec<Value>)> {
+        self.build_typed_where_clause(bind_index, None)
+    }
+
+    // same as build_where_caluse, but the values get checked and converted
+    // the same way as the values of an insert if the table columns are known
+    pub fn build_typed_where_clause(
+        &self,
+        bind_index: &mut usize,
+        table_columns: Option<&TableColumns>,
+    ) -> Result<(String, Vec<Value>)> {
         let mut bind_values = Vec::new();
+        let mut conditions = Vec::new();
 
-        let where_clause_sql: String = if let Some(where_clause) = &self.where_clause {
-            match where_clause {
-                WhereClause::And(map) | WhereClause::Single(map) => {
-                    let conditions: Vec<String> = map
-                        .iter()
-                        .map(|(column, value)| {
-                            let sanitized_column = Self::sanitize_column_name(column)?;
-                            bind_values.push(value.clone());
-                            *bind_index += 1;
-                            Ok(format!("{} = ${}", sanitized_column, *bind_index - 1))
-                        })
-                        .collect::<Result<Vec<String>>>()?;
-
-                    format!(" WHERE {}", conditions.join(" AND "))
-                }
+        if let Some(where_clause) = &self.where_clause {
+            conditions.push(where_clause.build_clause(
+                bind_index,
+                &mut bind_values,
+                table_columns,
+            )?);
+        }
 
-                WhereClause::Or(map) => {
-                    let conditions: Vec<String> = map
-                        .iter()
-                        .map(|(column, value)| {
-                            let sanitized_column = Self::sanitize_column_name(column)?;
-                            bind_values.push(value.clone());
-                            *bind_index += 1;
-                            Ok(format!("{} = ${}", sanitized_column, *bind_index - 1))
-                        })
-                        .collect::<Result<Vec<String>>>()?;
-
-                    format!(" WHERE ({})", conditions.join(" OR "))
-                }
-            }
-        } else {
+        if let Some(search) = &self.search {
+            let (document, query) = search.build(bind_index, &mut bind_values, table_columns)?;
+            conditions.push(format!("{} @@ {}", document, query));
+        }
+
+        let where_clause_sql: String = if conditions.is_empty() {
             String::new()
+        } else {
+            format!(" WHERE {}", conditions.join(" AND "))
         };
 
         Ok((where_clause_sql, bind_values))
     }
 
+    // looks up the type of a (possibly qualified) column
+    // qualified columns of tables that are not part of table_columns stay untyped
+    fn column_type<'a>(
+        column: &str,
+        table_columns: Option<&'a TableColumns>,
+    ) -> Result<Option<&'a ColumnType>> {
+        let table_columns = match table_columns {
+            Some(table_columns) => table_columns,
+            None => return Ok(None),
+        };
+
+        if let Some(column_type) = table_columns.get(column) {
+            return Ok(Some(column_type));
+        }
+
+        let known_table = match column.split_once('.') {
+            Some((table, _)) => {
+                let prefix = format!("{}.", table);
+                table_columns.keys().any(|key| key.starts_with(&prefix))
+            }
+            None => true,
+        };
+        if known_table {
+            Err(anyhow!("Column {} does not exist", column))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // build_order_by with the search rank in front of it if that was requested
+    // the rank needs the search terms again, so they get bound a second time
+    pub fn build_typed_order_by(
+        &self,
+        bind_index: &mut usize,
+        table_columns: Option<&TableColumns>,
+    ) -> Result<(String, Vec<Value>)> {
+        let mut bind_values = Vec::new();
+
+        let search = match &self.search {
+            Some(search) if search.rank => search,
+            _ => return Ok((self.build_order_by()?, bind_values)),
+        };
+
+        let (document, query) = search.build(bind_index, &mut bind_values, table_columns)?;
+        let mut order_by_sql = format!(" ORDER BY ts_rank({}, {}) DESC", document, query);
+
+        // the requested order decides between rows with the same rank
+        if let Some(order_by) = self.build_order_by()?.strip_prefix(" ORDER BY ") {
+            order_by_sql.push_str(&format!(", {}", order_by));
+        }
+
+        Ok((order_by_sql, bind_values))
+    }
+
     pub fn build_order_by(&self) -> Result<String> {
-        if let Some(order_by) = &self.order_by {
-            let sanitized_column = Self::sanitize_column_name(&order_by.column)?;
-            let direction = match order_by.direction {
-                OrderDirection::Asc => "ASC",
-                OrderDirection::Desc => "DESC",
+        match &self.order_by {
+            Some(order_by) if !order_by.is_empty() => {
+                let keys = order_by
+                    .iter()
+                    .map(|key| {
+                        let sanitized_column = Self::sanitize_column_ref(&key.column)?;
+                        let direction = match key.direction {
+                            OrderDirection::Asc => "ASC",
+                            OrderDirection::Desc => "DESC",
+                        };
+                        let nulls = match key.nulls {
+                            Some(NullsOrder::First) => " NULLS FIRST",
+                            Some(NullsOrder::Last) => " NULLS LAST",
+                            None => "",
+                        };
+                        Ok(format!("{} {}{}", sanitized_column, direction, nulls))
+                    })
+                    .collect::<Result<Vec<String>>>()?;
+                Ok(format!(" ORDER BY {}", keys.join(", ")))
+            }
+            _ => Ok(String::new()), // no ORDER BY clause if not specified
+        }
+    }
+
+    // the rows that come after the cursor in the order of order_by (keyset pagination)
+    // (a > x) OR (a = x AND b > y) OR ... with < for descending keys
+    pub fn build_cursor_condition(
+        &self,
+        bind_index: &mut usize,
+        table_columns: Option<&TableColumns>,
+    ) -> Result<Option<(String, Vec<Value>)>> {
+        let cursor = match &self.cursor {
+            Some(cursor) => Cursor::decode(cursor)?,
+            None => return Ok(None),
+        };
+
+        let order_by = self.order_by.as_deref().unwrap_or_default();
+        if order_by.is_empty() {
+            return Err(anyhow!("A cursor requires order_by"));
+        }
+        if cursor.order_by != order_by || cursor.values.len() != order_by.len() {
+            return Err(anyhow!("The cursor belongs to a different order_by"));
+        }
+        if self.offset.is_some() {
+            return Err(anyhow!("A cursor cannot be combined with offset"));
+        }
+        if self.search.as_ref().is_some_and(|search| search.rank) {
+            return Err(anyhow!("A cursor cannot be combined with a ranked search"));
+        }
+
+        let mut bind_values = Vec::new();
+        let mut alternatives = Vec::new();
+        let mut equal_before: Vec<String> = Vec::new();
+
+        for (i, (key, value)) in order_by.iter().zip(cursor.values).enumerate() {
+            let column = Self::sanitize_column_ref(&key.column)?;
+            let column_type = Self::column_type(&key.column, table_columns)?;
+            let nulls_first = key.nulls_first();
+            // nothing comes after the last key, so its equality is never needed
+            let last_key = i + 1 == order_by.len();
+
+            let (after, equal) = if value.is_null() {
+                // only non null values can follow a null that sorts first
+                let after = nulls_first.then(|| format!("{} IS NOT NULL", column));
+                (after, Some(format!("{} IS NULL", column)))
+            } else {
+                let op = match key.direction {
+                    OrderDirection::Asc => Comparison::Gt(value.clone()),
+                    OrderDirection::Desc => Comparison::Lt(value.clone()),
+                };
+                let after =
+                    build_comparison(&column, &op, column_type, bind_index, &mut bind_values)?;
+                let after = if nulls_first {
+                    after
+                } else {
+                    format!("({} OR {} IS NULL)", after, column)
+                };
+                let equal = if last_key {
+                    None
+                } else {
+                    Some(build_comparison(
+                        &column,
+                        &Comparison::Eq(value),
+                        column_type,
+                        bind_index,
+                        &mut bind_values,
+                    )?)
+                };
+                (Some(after), equal)
             };
-            Ok(format!(" ORDER BY {} {}", sanitized_column, direction))
-        } else {
-            Ok(String::new()) // no ORDER BY clause if not specified
+
+            if let Some(after) = after {
+                let mut parts = equal_before.clone();
+                parts.push(after);
+                alternatives.push(format!("({})", parts.join(" AND ")));
+            }
+            equal_before.extend(equal);
         }
+
+        // the cursor was at the very end, e.g. a trailing null that sorts last
+        let condition = if alternatives.is_empty() {
+            String::from("FALSE")
+        } else {
+            format!("({})", alternatives.join(" OR "))
+        };
+
+        Ok(Some((condition, bind_values)))
     }
 
     pub fn build_limit(&self) -> Result<String> {
@@ -339,3 +1485,1200 @@ impl Filters {
         }
     }
 }
+
+impl Search {
+    // renders the tsvector of the columns and the tsquery, in that order
+    fn build(
+        &self,
+        bind_index: &mut usize,
+        bind_values: &mut Vec<Value>,
+        table_columns: Option<&TableColumns>,
+    ) -> Result<(String, String)> {
+        if self.columns.is_empty() {
+            return Err(anyhow!("Search requires at least one column"));
+        }
+        if self.query.trim().is_empty() {
+            return Err(anyhow!("Search query cannot be empty"));
+        }
+
+        let columns = self
+            .columns
+            .iter()
+            .map(|column| {
+                Filters::column_type(column, table_columns)?;
+                Filters::sanitize_column_ref(column)
+            })
+            .collect::<Result<Vec<String>>>()?;
+
+        // the language is a value as well, the cast makes postgres look up the config
+        let language = self.language.as_deref().unwrap_or("simple");
+        bind_values.push(Value::String(language.to_string()));
+        let language_placeholder = format!("${}::regconfig", *bind_index);
+        *bind_index += 1;
+
+        bind_values.push(Value::String(self.query.clone()));
+        let query_placeholder = format!("${}", *bind_index);
+        *bind_index += 1;
+
+        // concat_ws skips NULL columns and takes any column type
+        let document = format!(
+            "to_tsvector({}, concat_ws(' ', {}))",
+            language_placeholder,
+            columns.join(", ")
+        );
+        let query = format!(
+            "plainto_tsquery({}, {})",
+            language_placeholder, query_placeholder
+        );
+
+        Ok((document, query))
+    }
+}
+
+impl WhereClause {
+    // renders the clause (without the leading WHERE) and pushes its values onto bind_values
+    // the bind index keeps counting through nested clauses so placeholders stay in order
+    pub fn build_clause(
+        &self,
+        bind_index: &mut usize,
+        bind_values: &mut Vec<Value>,
+        table_columns: Option<&TableColumns>,
+    ) -> Result<String> {
+        match self {
+            WhereClause::And(map) | WhereClause::Single(map) => {
+                Ok(Self::build_equals(map, bind_index, bind_values, table_columns)?.join(" AND "))
+            }
+            WhereClause::Or(map) => Ok(format!(
+                "({})",
+                Self::build_equals(map, bind_index, bind_values, table_columns)?.join(" OR ")
+            )),
+            WhereClause::All(nodes) => {
+                Ok(Self::build_nodes(nodes, bind_index, bind_values, table_columns)?.join(" AND "))
+            }
+            WhereClause::Any(nodes) => Ok(format!(
+                "({})",
+                Self::build_nodes(nodes, bind_index, bind_values, table_columns)?.join(" OR ")
+            )),
+            WhereClause::Not(node) => Ok(format!(
+                "NOT ({})",
+                node.build_node(bind_index, bind_values, table_columns)?
+            )),
+        }
+    }
+
+    fn build_equals(
+        map: &HashMap<String, Value>,
+        bind_index: &mut usize,
+        bind_values: &mut Vec<Value>,
+        table_columns: Option<&TableColumns>,
+    ) -> Result<Vec<String>> {
+        if map.is_empty() {
+            return Err(anyhow!("Where clause requires at least one condition"));
+        }
+
+        map.iter()
+            .map(|(column, value)| {
+                let sanitized_column = Filters::sanitize_column_ref(column)?;
+                let column_type = Filters::column_type(column, table_columns)?;
+                build_comparison(
+                    &sanitized_column,
+                    &Comparison::Eq(value.clone()),
+                    column_type,
+                    bind_index,
+                    bind_values,
+                )
+            })
+            .collect()
+    }
+
+    fn build_nodes(
+        nodes: &[WhereNode],
+        bind_index: &mut usize,
+        bind_values: &mut Vec<Value>,
+        table_columns: Option<&TableColumns>,
+    ) -> Result<Vec<String>> {
+        if nodes.is_empty() {
+            return Err(anyhow!("Where clause requires at least one condition"));
+        }
+
+        nodes
+            .iter()
+            .map(|node| node.build_node(bind_index, bind_values, table_columns))
+            .collect()
+    }
+}
+
+impl WhereNode {
+    pub fn build_node(
+        &self,
+        bind_index: &mut usize,
+        bind_values: &mut Vec<Value>,
+        table_columns: Option<&TableColumns>,
+    ) -> Result<String> {
+        match self {
+            WhereNode::Condition(condition) => {
+                condition.build_condition(bind_index, bind_values, table_columns)
+            }
+            WhereNode::Clause(clause) => {
+                let sql = clause.build_clause(bind_index, bind_values, table_columns)?;
+                // Or / Any already come in parentheses, everything joined by AND gets
+                // wrapped so it keeps its meaning inside the parent clause
+                match clause {
+                    WhereClause::And(_) | WhereClause::Single(_) | WhereClause::All(_) => {
+                        Ok(format!("({})", sql))
+                    }
+                    _ => Ok(sql),
+                }
+            }
+        }
+    }
+}
+
+impl Condition {
+    pub fn build_condition(
+        &self,
+        bind_index: &mut usize,
+        bind_values: &mut Vec<Value>,
+        table_columns: Option<&TableColumns>,
+    ) -> Result<String> {
+        let column = Filters::sanitize_column_ref(&self.column)?;
+        let column_type = Filters::column_type(&self.column, table_columns)?;
+
+        let path = match &self.path {
+            Some(path) => path,
+            None => {
+                match (&self.op, column_type) {
+                    (Comparison::Contains(_) | Comparison::HasKey(_), Some(column_type))
+                        if !column_type.is_jsonb() =>
+                    {
+                        return Err(anyhow!(
+                            "Contains and HasKey require a jsonb column, {} is {}",
+                            self.column,
+                            column_type.data_type
+                        ));
+                    }
+                    // postgres has no LIKE for other types, that would only fail once it runs
+                    (Comparison::Like(_) | Comparison::ILike(_), Some(column_type))
+                        if !column_type.is_text() =>
+                    {
+                        return Err(anyhow!(
+                            "Like and ILike require a text or varchar column, {} is {}",
+                            self.column,
+                            column_type.data_type
+                        ));
+                    }
+                    _ => {}
+                }
+                return build_comparison(&column, &self.op, column_type, bind_index, bind_values);
+            }
+        };
+
+        if let Some(column_type) = column_type {
+            if !column_type.is_json() {
+                return Err(anyhow!(
+                    "A path requires a json or jsonb column, {} is {}",
+                    self.column,
+                    column_type.data_type
+                ));
+            }
+        }
+        if path.is_empty() {
+            return Err(anyhow!(
+                "The path on column {} cannot be empty",
+                self.column
+            ));
+        }
+        // ->> gives back text, so 10 > 5 would be compared as "10" > "5"
+        if matches!(
+            self.op,
+            Comparison::Lt(_)
+                | Comparison::Lte(_)
+                | Comparison::Gt(_)
+                | Comparison::Gte(_)
+                | Comparison::Between(_, _)
+        ) {
+            return Err(anyhow!(
+                "Lt, Lte, Gt, Gte and Between are not supported on a path of column {}",
+                self.column
+            ));
+        }
+
+        // Contains and HasKey work on the json value itself,
+        // everything else compares the text of it (->> on the last key)
+        let as_json = matches!(self.op, Comparison::Contains(_) | Comparison::HasKey(_));
+
+        let mut expression = column;
+        for (i, key) in path.iter().enumerate() {
+            let operator = if i + 1 == path.len() && !as_json {
+                "->>"
+            } else {
+                "->"
+            };
+            bind_values.push(Value::String(key.clone()));
+            expression = format!("{} {} ${}::text", expression, operator, *bind_index);
+            *bind_index += 1;
+        }
+
+        if as_json {
+            // json columns give back json, @> and ? only exist for jsonb
+            let expression = format!("({})::jsonb", expression);
+            build_comparison(&expression, &self.op, None, bind_index, bind_values)
+        } else {
+            let expression = format!("({})", expression);
+            build_comparison(
+                &expression,
+                &self.op.as_text(),
+                None,
+                bind_index,
+                bind_values,
+            )
+        }
+    }
+}
+
+impl Comparison {
+    // the same comparison with every value turned into the text ->> gives back,
+    // e.g. 5 becomes "5" and true becomes "true"
+    fn as_text(&self) -> Comparison {
+        let text = |value: &Value| match value {
+            Value::String(_) | Value::Null => value.clone(),
+            _ => Value::String(value.to_string()),
+        };
+
+        match self {
+            Comparison::Eq(value) => Comparison::Eq(text(value)),
+            Comparison::NotEq(value) => Comparison::NotEq(text(value)),
+            Comparison::In(values) => Comparison::In(values.iter().map(text).collect()),
+            other => other.clone(),
+        }
+    }
+}
+
+impl OrderBy {
+    pub fn nulls_first(&self) -> bool {
+        match self.nulls {
+            Some(nulls) => nulls == NullsOrder::First,
+            // by default postgres treats nulls as larger than any value
+            None => self.direction == OrderDirection::Desc,
+        }
+    }
+}
+
+impl AggregateFunction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AggregateFunction::Count => "COUNT",
+            AggregateFunction::Sum => "SUM",
+            AggregateFunction::Avg => "AVG",
+            AggregateFunction::Min => "MIN",
+            AggregateFunction::Max => "MAX",
+        }
+    }
+}
+
+impl AggregateExpr {
+    fn default_alias(&self) -> String {
+        let function = self.function.as_str().to_ascii_lowercase();
+        match &self.column {
+            Some(column) => format!("{}_{}", function, column),
+            None => function,
+        }
+    }
+}
+
+impl JoinKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JoinKind::Inner => "INNER JOIN",
+            JoinKind::Left => "LEFT JOIN",
+        }
+    }
+}
+
+fn check_model_column(entry: &ModelEntry, table: &str, column: &str) -> Result<()> {
+    Filters::sanitize_column_name(column)?;
+    check_model_declares_columns(&entry.columns, table)?;
+    if !entry.columns.iter().any(|c| c.name == column) {
+        return Err(anyhow!(
+            "Column {} does not exist in table {}",
+            column,
+            table
+        ));
+    }
+    Ok(())
+}
+
+// models without columns leave the column types of their table unknown
+pub(crate) fn check_model_declares_columns(columns: &[ColumnDef], table: &str) -> Result<()> {
+    if columns.is_empty() {
+        return Err(anyhow!(
+            "The model of table {} declares no columns (TableModel::columns)",
+            table
+        ));
+    }
+    Ok(())
+}
+
+// renders `column <op> placeholder(s)` and pushes the values onto bind_values
+// every value gets its own placeholder, nothing is embedded into the sql
+// the column has to be sanitized (or be a trusted expression) already
+// if the column type is known the values get converted and the placeholders cast like on insert
+fn build_comparison(
+    column: &str,
+    op: &Comparison,
+    column_type: Option<&ColumnType>,
+    bind_index: &mut usize,
+    bind_values: &mut Vec<Value>,
+) -> Result<String> {
+    let mut bind = |value: &Value| -> Result<String> {
+        let placeholder = match column_type {
+            Some(column_type) => {
+                bind_values.push(column_type.convert(column, value)?);
+                column_type.placeholder(*bind_index)?
+            }
+            None => {
+                bind_values.push(value.clone());
+                format!("${}", *bind_index)
+            }
+        };
+        *bind_index += 1;
+        Ok(placeholder)
+    };
+
+    let condition = match op {
+        Comparison::Eq(value) => format!("{} = {}", column, bind(value)?),
+        Comparison::NotEq(value) => format!("{} <> {}", column, bind(value)?),
+        Comparison::Lt(value) => format!("{} < {}", column, bind(value)?),
+        Comparison::Lte(value) => format!("{} <= {}", column, bind(value)?),
+        Comparison::Gt(value) => format!("{} > {}", column, bind(value)?),
+        Comparison::Gte(value) => format!("{} >= {}", column, bind(value)?),
+        Comparison::Like(pattern) | Comparison::ILike(pattern) => {
+            // patterns are always text, no matter the column type
+            bind_values.push(Value::String(pattern.clone()));
+            *bind_index += 1;
+            let operator = if let Comparison::Like(_) = op {
+                "LIKE"
+            } else {
+                "ILIKE"
+            };
+            format!("{} {} ${}", column, operator, *bind_index - 1)
+        }
+        Comparison::In(values) => {
+            // postgres does not accept an empty IN () list
+            if values.is_empty() {
+                return Err(anyhow!(
+                    "In on column {} requires at least one value",
+                    column
+                ));
+            }
+            let placeholders = values
+                .iter()
+                .map(&mut bind)
+                .collect::<Result<Vec<String>>>()?;
+            format!("{} IN ({})", column, placeholders.join(", "))
+        }
+        Comparison::IsNull => format!("{} IS NULL", column),
+        Comparison::IsNotNull => format!("{} IS NOT NULL", column),
+        Comparison::Between(low, high) => {
+            let low = bind(low)?;
+            let high = bind(high)?;
+            format!("{} BETWEEN {} AND {}", column, low, high)
+        }
+        Comparison::Contains(value) => {
+            // the json goes in as its text and gets cast, whatever the column type is
+            bind_values.push(Value::String(value.to_string()));
+            *bind_index += 1;
+            format!("{} @> ${}::jsonb", column, *bind_index - 1)
+        }
+        Comparison::HasKey(key) => {
+            bind_values.push(Value::String(key.clone()));
+            *bind_index += 1;
+            format!("{} ? ${}", column, *bind_index - 1)
+        }
+    };
+
+    Ok(condition)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::ModelRegistry;
+    use crate::models::api::users::User;
+    use crate::models::auth::{AuthTokens, AuthUser};
+    use crate::models::db::{PolicyAction, QueryPolicy};
+    use serde_json::json;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    fn registry() -> Arc<ModelRegistry> {
+        let mut registry = ModelRegistry::new();
+        registry.register::<User>();
+        registry.register::<AuthUser>();
+        registry.register::<AuthTokens>();
+        Arc::new(registry)
+    }
+
+    // a query builder set up the way the QueryExecutor does it
+    fn request_builder(request: Value, owner: Option<Value>) -> QueryBuilder {
+        let mut request: DatabaseRequest = serde_json::from_value(request).unwrap();
+        request.owner = owner;
+        let registry = registry();
+        let table_columns = registry.table_columns(&request.table);
+        let mut query_builder = QueryBuilder::from_request(request, table_columns);
+        query_builder.model_registry = Some(registry);
+        query_builder.allowed_tables = Some(HashSet::new());
+        query_builder
+    }
+
+    fn key(column: &str, direction: OrderDirection, nulls: Option<NullsOrder>) -> OrderBy {
+        OrderBy {
+            column: column.to_string(),
+            direction,
+            nulls,
+        }
+    }
+
+    fn cursor_filters(order_by: Vec<OrderBy>, values: Vec<Value>) -> Filters {
+        let cursor = Cursor {
+            order_by: order_by.clone(),
+            values,
+        };
+        Filters {
+            order_by: Some(order_by),
+            cursor: Some(cursor.encode().unwrap()),
+            ..Default::default()
+        }
+    }
+
+    fn table_columns() -> TableColumns {
+        HashMap::from([
+            (String::from("id"), ColumnType::new("bigint")),
+            (String::from("score"), ColumnType::nullable("integer")),
+        ])
+    }
+
+    fn cursor_condition(filters: &Filters) -> Result<(String, Vec<Value>, usize)> {
+        let table_columns = table_columns();
+        let mut bind_index = 1;
+        let (condition, bind_values) = filters
+            .build_cursor_condition(&mut bind_index, Some(&table_columns))?
+            .expect("a cursor was given");
+        Ok((condition, bind_values, bind_index))
+    }
+
+    #[test]
+    fn cursor_condition_single_ascending_key() {
+        let filters = cursor_filters(vec![key("id", OrderDirection::Asc, None)], vec![json!(5)]);
+
+        let (condition, bind_values, bind_index) = cursor_condition(&filters).unwrap();
+
+        // ascending sorts nulls last, so they still follow the cursor
+        assert_eq!(condition, r#"((("id" > $1::bigint OR "id" IS NULL)))"#);
+        assert_eq!(bind_values, vec![json!(5)]);
+        assert_eq!(bind_index, 2);
+    }
+
+    #[test]
+    fn cursor_condition_mixed_directions() {
+        let filters = cursor_filters(
+            vec![
+                key("score", OrderDirection::Desc, None),
+                key("id", OrderDirection::Asc, None),
+            ],
+            vec![json!(7), json!(3)],
+        );
+
+        let (condition, bind_values, _) = cursor_condition(&filters).unwrap();
+
+        // descending sorts nulls first, so they cannot follow a non null score
+        assert_eq!(
+            condition,
+            r#"(("score" < $1::integer) OR ("score" = $2::integer AND ("id" > $3::bigint OR "id" IS NULL)))"#
+        );
+        assert_eq!(bind_values, vec![json!(7), json!(7), json!(3)]);
+    }
+
+    #[test]
+    fn cursor_condition_explicit_nulls_order() {
+        let filters = cursor_filters(
+            vec![
+                key("score", OrderDirection::Asc, Some(NullsOrder::First)),
+                key("id", OrderDirection::Desc, Some(NullsOrder::Last)),
+            ],
+            vec![json!(2), json!(9)],
+        );
+
+        let (condition, bind_values, _) = cursor_condition(&filters).unwrap();
+
+        assert_eq!(
+            condition,
+            r#"(("score" > $1::integer) OR ("score" = $2::integer AND ("id" < $3::bigint OR "id" IS NULL)))"#
+        );
+        assert_eq!(bind_values, vec![json!(2), json!(2), json!(9)]);
+    }
+
+    #[test]
+    fn cursor_condition_null_value_sorting_first() {
+        let filters = cursor_filters(
+            vec![
+                key("score", OrderDirection::Asc, Some(NullsOrder::First)),
+                key("id", OrderDirection::Asc, None),
+            ],
+            vec![Value::Null, json!(4)],
+        );
+
+        let (condition, bind_values, _) = cursor_condition(&filters).unwrap();
+
+        // every non null score follows, and the nulls after the cursor's id
+        assert_eq!(
+            condition,
+            r#"(("score" IS NOT NULL) OR ("score" IS NULL AND ("id" > $1::bigint OR "id" IS NULL)))"#
+        );
+        assert_eq!(bind_values, vec![json!(4)]);
+    }
+
+    #[test]
+    fn cursor_condition_null_value_sorting_last() {
+        let filters = cursor_filters(
+            vec![
+                key("score", OrderDirection::Asc, None),
+                key("id", OrderDirection::Asc, None),
+            ],
+            vec![Value::Null, json!(4)],
+        );
+
+        let (condition, bind_values, _) = cursor_condition(&filters).unwrap();
+
+        // only the remaining null scores with a larger id can follow
+        assert_eq!(
+            condition,
+            r#"(("score" IS NULL AND ("id" > $1::bigint OR "id" IS NULL)))"#
+        );
+        assert_eq!(bind_values, vec![json!(4)]);
+    }
+
+    #[test]
+    fn cursor_condition_at_the_very_end() {
+        let filters = cursor_filters(
+            vec![key("score", OrderDirection::Asc, None)],
+            vec![Value::Null],
+        );
+
+        let (condition, bind_values, _) = cursor_condition(&filters).unwrap();
+
+        assert_eq!(condition, "FALSE");
+        assert!(bind_values.is_empty());
+    }
+
+    #[test]
+    fn cursor_condition_untyped_columns() {
+        let filters = cursor_filters(
+            vec![key("id", OrderDirection::Desc, None)],
+            vec![json!("a")],
+        );
+
+        let mut bind_index = 1;
+        let (condition, bind_values) = filters
+            .build_cursor_condition(&mut bind_index, None)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(condition, r#"(("id" < $1))"#);
+        assert_eq!(bind_values, vec![json!("a")]);
+    }
+
+    #[test]
+    fn cursor_condition_continues_bind_index() {
+        let filters = cursor_filters(vec![key("id", OrderDirection::Asc, None)], vec![json!(5)]);
+
+        let mut bind_index = 4;
+        let (condition, _) = filters
+            .build_cursor_condition(&mut bind_index, Some(&table_columns()))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(condition, r#"((("id" > $4::bigint OR "id" IS NULL)))"#);
+        assert_eq!(bind_index, 5);
+    }
+
+    #[test]
+    fn cursor_condition_without_cursor() {
+        let filters = Filters::default();
+        let mut bind_index = 1;
+        assert!(filters
+            .build_cursor_condition(&mut bind_index, None)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn cursor_condition_rejects_other_order_by() {
+        let mut filters =
+            cursor_filters(vec![key("id", OrderDirection::Asc, None)], vec![json!(5)]);
+        filters.order_by = Some(vec![key("id", OrderDirection::Desc, None)]);
+
+        let error = cursor_condition(&filters).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The cursor belongs to a different order_by"
+        );
+    }
+
+    #[test]
+    fn cursor_condition_rejects_offset_and_missing_order_by() {
+        let mut filters =
+            cursor_filters(vec![key("id", OrderDirection::Asc, None)], vec![json!(5)]);
+        filters.offset = Some(10);
+        let error = cursor_condition(&filters).unwrap_err();
+        assert_eq!(error.to_string(), "A cursor cannot be combined with offset");
+
+        filters.offset = None;
+        filters.order_by = None;
+        let error = cursor_condition(&filters).unwrap_err();
+        assert_eq!(error.to_string(), "A cursor requires order_by");
+    }
+
+    #[test]
+    fn cursor_condition_rejects_invalid_cursor() {
+        let filters = Filters {
+            order_by: Some(vec![key("id", OrderDirection::Asc, None)]),
+            cursor: Some(String::from("not a cursor")),
+            ..Default::default()
+        };
+
+        let error = cursor_condition(&filters).unwrap_err();
+        assert_eq!(error.to_string(), "Invalid cursor");
+    }
+
+    #[test]
+    fn owner_is_never_taken_from_the_request() {
+        let request: DatabaseRequest = serde_json::from_value(json!({
+            "table": "auth_tokens",
+            "action": "Retrieve",
+            "owner": 1
+        }))
+        .unwrap();
+
+        assert_eq!(request.owner, None);
+    }
+
+    #[test]
+    fn owner_requires_model_registry() {
+        let mut query_builder = QueryBuilder::new(
+            String::from("auth_tokens"),
+            DatabaseAction::Retrieve,
+            None,
+            None,
+            None,
+            None,
+        );
+        query_builder.owner = Some(json!(1));
+
+        assert_eq!(
+            query_builder.build_query().unwrap_err().to_string(),
+            "An owner requires a model registry"
+        );
+    }
+
+    #[test]
+    fn delete_without_where_on_behalf_of_an_owner() {
+        let mut query_builder = request_builder(
+            json!({"table": "auth_tokens", "action": {"Delete": "DeleteValue"}}),
+            Some(json!(7)),
+        );
+
+        // only the tokens of the owner are gone, so this is no truncate
+        assert_eq!(
+            PolicyAction::from_query_builder(&query_builder),
+            Some(PolicyAction::DeleteValue)
+        );
+        assert_eq!(QueryPolicy::default().check(&query_builder), Ok(()));
+        assert_eq!(
+            query_builder.build_query().unwrap(),
+            (
+                String::from(r#"DELETE FROM "auth_tokens" WHERE "auth_tokens"."uid" = $1::bigint"#),
+                vec![json!(7)]
+            )
+        );
+    }
+
+    #[test]
+    fn soft_delete_without_where() {
+        let mut query_builder = request_builder(
+            json!({"table": "users", "action": {"Delete": "DeleteValue"}}),
+            Some(json!(7)),
+        );
+
+        assert_eq!(
+            PolicyAction::from_query_builder(&query_builder),
+            Some(PolicyAction::DeleteValue)
+        );
+        assert_eq!(QueryPolicy::default().check(&query_builder), Ok(()));
+        assert_eq!(
+            query_builder.build_query().unwrap().0,
+            r#"UPDATE "users" SET "deleted_at" = now() WHERE "users"."deleted_at" IS NULL AND "users"."uid" = $1::bigint"#
+        );
+    }
+
+    #[test]
+    fn delete_without_where_truncates() {
+        let mut query_builder = request_builder(
+            json!({"table": "auth_tokens", "action": {"Delete": "DeleteValue"}}),
+            None,
+        );
+
+        assert_eq!(
+            PolicyAction::from_query_builder(&query_builder),
+            Some(PolicyAction::Truncate)
+        );
+        assert_eq!(
+            QueryPolicy::default().check(&query_builder),
+            Err(String::from("Truncate is not allowed on table auth_tokens"))
+        );
+        assert!(query_builder
+            .build_query()
+            .unwrap_err()
+            .to_string()
+            .ends_with("this requires confirm_destructive"));
+
+        query_builder.confirm_destructive = true;
+        assert_eq!(
+            query_builder.build_query().unwrap().0,
+            r#"TRUNCATE TABLE "auth_tokens""#
+        );
+    }
+
+    #[test]
+    fn purge_without_where_on_behalf_of_an_owner() {
+        let query_builder = request_builder(
+            json!({"table": "users", "action": {"Delete": "Purge"}}),
+            Some(json!(7)),
+        );
+
+        // still a purge, which the default policy denies
+        assert_eq!(
+            PolicyAction::from_query_builder(&query_builder),
+            Some(PolicyAction::Purge)
+        );
+        assert_eq!(
+            QueryPolicy::default().check(&query_builder),
+            Err(String::from("Purge is not allowed on table users"))
+        );
+    }
+
+    #[test]
+    fn aggregate_cursor_requires_group_by_keys() {
+        let aggregate = json!({
+            "Aggregate": {
+                "aggregates": [{"function": "Count", "column": null, "alias": null}],
+                "group_by": ["email_verified"]
+            }
+        });
+        let request = |order_by: &str| {
+            let filters = cursor_filters(
+                vec![key(order_by, OrderDirection::Asc, None)],
+                vec![json!(true)],
+            );
+            json!({"table": "users", "action": aggregate, "filters": filters})
+        };
+
+        let mut query_builder = request_builder(request("count"), None);
+        assert_eq!(
+            query_builder.build_query().unwrap_err().to_string(),
+            "A cursor on an aggregate requires every order_by column to be a group_by column"
+        );
+
+        let mut query_builder = request_builder(request("email_verified"), None);
+        let (query, bind_params) = query_builder.build_query().unwrap();
+        assert_eq!(
+            query,
+            r#"SELECT "email_verified", COUNT(*) AS "count" FROM "users" WHERE "users"."deleted_at" IS NULL AND ((("email_verified" > $1::boolean OR "email_verified" IS NULL))) GROUP BY "email_verified" ORDER BY "email_verified" ASC"#
+        );
+        assert_eq!(bind_params, vec![json!(true)]);
+    }
+
+    fn bulk_insert(rows: Vec<Value>) -> QueryBuilder {
+        let rows = rows
+            .into_iter()
+            .map(|row| serde_json::from_value(row).unwrap())
+            .collect();
+        let table_columns = HashMap::from([
+            (String::from("a"), ColumnType::new("integer")),
+            (String::from("b"), ColumnType::new("integer")),
+            (String::from("c"), ColumnType::new("integer")),
+        ]);
+        QueryBuilder::new(
+            String::from("numbers"),
+            DatabaseAction::BulkInsert,
+            Some(rows),
+            None,
+            Some(table_columns),
+            None,
+        )
+    }
+
+    fn bulk_rows(count: usize) -> Vec<Value> {
+        (0..count)
+            .map(|i| json!({"a": i, "b": i, "c": i}))
+            .collect()
+    }
+
+    #[test]
+    fn bulk_insert_fits_into_one_statement() {
+        let rows_per_query = MAX_BIND_PARAMS / 3;
+
+        let queries = bulk_insert(bulk_rows(rows_per_query))
+            .build_bulk_insert_queries()
+            .unwrap();
+
+        assert_eq!(queries.len(), 1);
+        assert_eq!(queries[0].1.len(), rows_per_query * 3);
+    }
+
+    #[test]
+    fn bulk_insert_splits_into_statements() {
+        let rows_per_query = MAX_BIND_PARAMS / 3;
+
+        let queries = bulk_insert(bulk_rows(rows_per_query + 1))
+            .build_bulk_insert_queries()
+            .unwrap();
+
+        assert_eq!(queries.len(), 2);
+        assert_eq!(queries[0].1.len(), rows_per_query * 3);
+        // every statement counts its placeholders from $1 again
+        assert_eq!(
+            queries[1],
+            (
+                String::from(
+                    r#"INSERT INTO "numbers" ("a", "b", "c") VALUES ($1::integer, $2::integer, $3::integer)"#
+                ),
+                vec![json!(rows_per_query); 3]
+            )
+        );
+    }
+
+    #[test]
+    fn bulk_insert_single_statement() {
+        let mut query_builder = bulk_insert(bulk_rows(2));
+
+        assert_eq!(
+            query_builder.build_query().unwrap(),
+            (
+                String::from(
+                    r#"INSERT INTO "numbers" ("a", "b", "c") VALUES ($1::integer, $2::integer, $3::integer), ($4::integer, $5::integer, $6::integer)"#
+                ),
+                vec![json!(0), json!(0), json!(0), json!(1), json!(1), json!(1)]
+            )
+        );
+    }
+
+    #[test]
+    fn bulk_insert_error_names_the_row() {
+        let rows_per_query = MAX_BIND_PARAMS / 3;
+        let mut rows = bulk_rows(rows_per_query + 1);
+        rows[rows_per_query]["b"] = json!("x");
+
+        let error = bulk_insert(rows).build_bulk_insert_queries().unwrap_err();
+
+        // the row counts over every statement, not within the one it ended up in
+        assert!(error
+            .to_string()
+            .starts_with(&format!("row {}: ", rows_per_query)));
+    }
+
+    #[test]
+    fn bulk_insert_rows_need_the_same_columns() {
+        let rows = vec![
+            json!({"a": 1, "b": 1}),
+            json!({"a": 2}),
+            json!({"a": 3, "b": 3, "c": 3}),
+        ];
+
+        let error = bulk_insert(rows).build_bulk_insert_queries().unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Every row of a bulk insert needs the same columns: row 1 is missing column(s) b; row 2 has column(s) c which row 0 does not have"
+        );
+    }
+
+    #[test]
+    fn bulk_insert_columns_have_to_exist() {
+        let mut row = serde_json::Map::new();
+        for i in 0..=MAX_BIND_PARAMS {
+            row.insert(format!("column_{}", i), json!(1));
+        }
+
+        // checked before the rows are split up by their number of columns
+        let error = bulk_insert(vec![Value::Object(row)])
+            .build_bulk_insert_queries()
+            .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Column column_0 does not exist in table numbers"
+        );
+    }
+
+    fn json_condition(path: Value, op: Value) -> Result<(String, Vec<Value>)> {
+        let condition: Condition =
+            serde_json::from_value(json!({"column": "preferences", "path": path, "op": op}))
+                .unwrap();
+        let table_columns =
+            HashMap::from([(String::from("preferences"), ColumnType::new("jsonb"))]);
+        let mut bind_index = 1;
+        let mut bind_values = Vec::new();
+        let sql =
+            condition.build_condition(&mut bind_index, &mut bind_values, Some(&table_columns))?;
+        Ok((sql, bind_values))
+    }
+
+    #[test]
+    fn json_path_compares_text() {
+        assert_eq!(
+            json_condition(json!(["theme", "size"]), json!({"In": [5, "large", null]})).unwrap(),
+            (
+                String::from(r#"("preferences" -> $1::text ->> $2::text) IN ($3, $4, $5)"#),
+                vec![
+                    json!("theme"),
+                    json!("size"),
+                    json!("5"),
+                    json!("large"),
+                    json!(null)
+                ]
+            )
+        );
+        assert_eq!(
+            json_condition(json!(["theme"]), json!({"HasKey": "color"})).unwrap(),
+            (
+                String::from(r#"("preferences" -> $1::text)::jsonb ? $2"#),
+                vec![json!("theme"), json!("color")]
+            )
+        );
+    }
+
+    #[test]
+    fn json_path_refuses_ordering_comparisons() {
+        for op in [
+            json!({"Gt": 5}),
+            json!({"Lte": "a"}),
+            json!({"Between": [1, 2]}),
+        ] {
+            assert_eq!(
+                json_condition(json!(["age"]), op).unwrap_err().to_string(),
+                "Lt, Lte, Gt, Gte and Between are not supported on a path of column preferences"
+            );
+        }
+    }
+
+    fn build_where(where_clause: Value) -> (String, Vec<Value>, usize) {
+        let where_clause: WhereClause = serde_json::from_value(where_clause).unwrap();
+        let table_columns = registry().table_columns("users");
+        let mut bind_index = 1;
+        let mut bind_values = Vec::new();
+        let sql = where_clause
+            .build_clause(&mut bind_index, &mut bind_values, table_columns.as_ref())
+            .unwrap();
+        (sql, bind_values, bind_index)
+    }
+
+    #[test]
+    fn where_tree_nested() {
+        let (sql, bind_values, bind_index) = build_where(json!({"All": [
+            {"Any": [
+                {"column": "uid", "op": {"Eq": 1}},
+                {"Not": {"column": "email", "op": {"Like": "%@example.com"}}}
+            ]},
+            {"Not": {"All": [
+                {"column": "username", "op": {"In": ["a", "b"]}},
+                {"Or": {"email_verified": true}}
+            ]}},
+            {"column": "uid", "op": {"Between": [1, 9]}}
+        ]}));
+
+        assert_eq!(
+            sql,
+            r#"("uid" = $1::bigint OR NOT ("email" LIKE $2)) AND NOT (("username" IN ($3::text, $4::text) AND ("email_verified" = $5::boolean))) AND "uid" BETWEEN $6::bigint AND $7::bigint"#
+        );
+        assert_eq!(
+            bind_values,
+            vec![
+                json!(1),
+                json!("%@example.com"),
+                json!("a"),
+                json!("b"),
+                json!(true),
+                json!(1),
+                json!(9)
+            ]
+        );
+        assert_eq!(bind_index, 8);
+    }
+
+    #[test]
+    fn where_flat_forms() {
+        let map = |column: &str, value: Value| HashMap::from([(column.to_string(), value)]);
+
+        let where_clause: WhereClause = serde_json::from_value(json!({"All": [
+            {"And": {"uid": 1}},
+            {"Or": {"email": "a"}},
+            {"Single": {"username": "b"}}
+        ]}))
+        .unwrap();
+        assert_eq!(
+            where_clause,
+            WhereClause::All(vec![
+                WhereNode::Clause(WhereClause::And(map("uid", json!(1)))),
+                WhereNode::Clause(WhereClause::Or(map("email", json!("a")))),
+                WhereNode::Clause(WhereClause::Single(map("username", json!("b")))),
+            ])
+        );
+
+        assert_eq!(
+            build_where(json!({"Single": {"uid": 1}})),
+            (String::from(r#""uid" = $1::bigint"#), vec![json!(1)], 2)
+        );
+        assert_eq!(
+            build_where(json!({"Or": {"email": "a"}})),
+            (String::from(r#"("email" = $1::text)"#), vec![json!("a")], 2)
+        );
+        assert_eq!(
+            build_where(json!({"All": [
+                {"And": {"uid": 1}},
+                {"Or": {"email": "a"}},
+                {"Single": {"username": "b"}}
+            ]})),
+            (
+                String::from(
+                    r#"("uid" = $1::bigint) AND ("email" = $2::text) AND ("username" = $3::text)"#
+                ),
+                vec![json!(1), json!("a"), json!("b")],
+                4
+            )
+        );
+    }
+
+    #[test]
+    fn where_followed_by_soft_delete_owner_and_cursor() {
+        let filters = cursor_filters(vec![key("uid", OrderDirection::Asc, None)], vec![json!(3)]);
+        let mut filters = serde_json::to_value(filters).unwrap();
+        filters["where"] = json!({"Any": [
+            {"column": "email", "op": {"Eq": "a"}},
+            {"column": "username", "op": {"Eq": "b"}}
+        ]});
+        let mut query_builder = request_builder(
+            json!({
+                "table": "users",
+                "action": "Retrieve",
+                "columns": ["uid"],
+                "filters": filters
+            }),
+            Some(json!(7)),
+        );
+
+        let (query, bind_params) = query_builder.build_query().unwrap();
+
+        // the Any keeps its parentheses, so the OR cannot swallow the conditions after it
+        assert_eq!(
+            query,
+            r#"SELECT "uid" FROM "users" WHERE ("email" = $1::text OR "username" = $2::text) AND "users"."deleted_at" IS NULL AND "users"."uid" = $3::bigint AND ((("uid" > $4::bigint OR "uid" IS NULL))) ORDER BY "uid" ASC"#
+        );
+        assert_eq!(
+            bind_params,
+            vec![json!("a"), json!("b"), json!(7), json!(3)]
+        );
+    }
+
+    // a table without a model, queryable only because it is allowed
+    fn allowed_table_builder(request: Value) -> QueryBuilder {
+        let table_columns = HashMap::from([
+            (String::from("order"), ColumnType::new("integer")),
+            (String::from("user"), ColumnType::nullable("text")),
+        ]);
+        let request: DatabaseRequest = serde_json::from_value(request).unwrap();
+        let mut query_builder = QueryBuilder::from_request(request, Some(table_columns));
+        query_builder.model_registry = Some(registry());
+        query_builder.allowed_tables = Some(HashSet::from([String::from("orders")]));
+        query_builder
+    }
+
+    #[test]
+    fn table_has_to_be_registered_or_allowed() {
+        let mut query_builder = request_builder(
+            json!({"table": "orders", "action": "Retrieve", "columns": ["order"]}),
+            None,
+        );
+        let error = query_builder.build_query().unwrap_err();
+        assert_eq!(
+            DatabaseError::from_anyhow(&error),
+            DatabaseError::permission_denied("Table orders is not allowed")
+        );
+
+        let mut query_builder = request_builder(
+            json!({"table": "orders; DROP TABLE users", "action": "Retrieve"}),
+            None,
+        );
+        assert_eq!(
+            query_builder.build_query().unwrap_err().to_string(),
+            "Invalid table name: orders; DROP TABLE users"
+        );
+    }
+
+    #[test]
+    fn reserved_words_are_quoted() {
+        let mut retrieve = allowed_table_builder(json!({
+            "table": "orders",
+            "action": "Retrieve",
+            "columns": ["order", "user"],
+            "filters": {"where": {"Single": {"user": "a"}}, "order_by": {"column": "order", "direction": "Desc"}}
+        }));
+        assert_eq!(
+            retrieve.build_query().unwrap().0,
+            r#"SELECT "order", "user" FROM "orders" WHERE "user" = $1::text ORDER BY "order" DESC"#
+        );
+
+        let mut update = allowed_table_builder(json!({
+            "table": "orders",
+            "action": "Update",
+            "values": {"user": "b"},
+            "filters": {"where": {"Single": {"order": 1}}},
+            "returning": ["order", "user"]
+        }));
+        assert_eq!(
+            update.build_query().unwrap().0,
+            r#"UPDATE "orders" SET "user" = $1::text WHERE "order" = $2::integer RETURNING "order", "user""#
+        );
+
+        let mut upsert = allowed_table_builder(json!({
+            "table": "orders",
+            "action": {"Upsert": {"target": ["user"], "action": {"DoUpdate": ["user"]}}},
+            "values": {"user": "a"},
+            "returning": ["order"]
+        }));
+        assert_eq!(
+            upsert.build_query().unwrap().0,
+            r#"INSERT INTO "orders" ("user") VALUES ($1::text) ON CONFLICT ("user") DO UPDATE SET "user" = EXCLUDED."user" RETURNING "order""#
+        );
+    }
+
+    #[test]
+    fn like_requires_a_text_column() {
+        assert_eq!(
+            build_where(json!({"All": [{"column": "username", "op": {"ILike": "a%"}}]})),
+            (String::from(r#""username" ILIKE $1"#), vec![json!("a%")], 2)
+        );
+
+        let where_clause: WhereClause =
+            serde_json::from_value(json!({"All": [{"column": "uid", "op": {"Like": "1%"}}]}))
+                .unwrap();
+        let table_columns = registry().table_columns("users");
+        let error = where_clause
+            .build_clause(&mut 1, &mut Vec::new(), table_columns.as_ref())
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Like and ILike require a text or varchar column, uid is bigint"
+        );
+    }
+}
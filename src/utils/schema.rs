@@ -0,0 +1,77 @@
+use crate::models::db::{ColumnType, SchemaCache, TableColumns};
+use anyhow::Result;
+use sqlx::{PgPool, Row};
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+
+impl SchemaCache {
+    pub fn new(pool: PgPool) -> Self {
+        Self {
+            pool,
+            tables: RwLock::new(HashMap::new()),
+        }
+    }
+
+    // creates the cache and loads the schema right away
+    pub async fn load(pool: PgPool) -> Result<Self> {
+        let schema_cache = Self::new(pool);
+        schema_cache.refresh().await?;
+        Ok(schema_cache)
+    }
+
+    // reloads the columns of all tables, call this after the schema changed
+    pub async fn refresh(&self) -> Result<()> {
+        // information_schema uses its own domain types, cast them so sqlx can decode them
+        let rows = sqlx::query(
+            "SELECT table_name::text, column_name::text, data_type::text, udt_name::text, \
+             is_nullable::text, character_maximum_length::int4 \
+             FROM information_schema.columns \
+             WHERE table_schema = current_schema() \
+             ORDER BY table_name, ordinal_position",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut tables: HashMap<String, TableColumns> = HashMap::new();
+
+        for row in rows {
+            let table_name: String = row.try_get("table_name")?;
+            let column_name: String = row.try_get("column_name")?;
+            let data_type: String = row.try_get("data_type")?;
+            let udt_name: String = row.try_get("udt_name")?;
+            let is_nullable: String = row.try_get("is_nullable")?;
+            let max_length: Option<i32> = row.try_get("character_maximum_length")?;
+
+            let data_type = match data_type.as_str() {
+                // udt_name of an array is the element type prefixed with an underscore, e.g. _int4
+                "ARRAY" => format!("{}[]", udt_name.trim_start_matches('_')),
+                "character varying" => match max_length {
+                    Some(max_length) => format!("varchar({})", max_length),
+                    None => String::from("varchar"),
+                },
+                "USER-DEFINED" => udt_name,
+                _ => data_type,
+            };
+
+            tables.entry(table_name).or_default().insert(
+                column_name,
+                ColumnType {
+                    data_type,
+                    nullable: is_nullable == "YES",
+                },
+            );
+        }
+
+        *self.tables.write().await = tables;
+
+        Ok(())
+    }
+
+    pub async fn table_columns(&self, table: &str) -> Option<TableColumns> {
+        self.tables.read().await.get(table).cloned()
+    }
+
+    pub async fn table_names(&self) -> Vec<String> {
+        self.tables.read().await.keys().cloned().collect()
+    }
+}
@@ -48,12 +48,17 @@ impl TokenHandler<UserClaims> for UserTokenHandler {
             action: DatabaseAction::Insert,
             values: Some(
                 AuthTokens {
-                    jti,
+                    jti: jti.clone(),
                     uid,
                     expires_at: exp as i64,
                 }
                 .as_hash_map(),
             ),
+            returning: Some(vec![
+                to_string_!("jti"),
+                to_string_!("uid"),
+                to_string_!("expires_at"),
+            ]),
             ..Default::default()
         };
 
@@ -81,8 +86,18 @@ impl TokenHandler<UserClaims> for UserTokenHandler {
                 return Err(anyhow!("{}", db_response.error_message().unwrap()));
             }
 
-            // we dont need to handle now anything else
-            // because at this point we can only receive a Success response.
+            // the inserted row comes back through RETURNING,
+            // make sure it is actually the token we just created
+            let inserted = db_response
+                .get_data()
+                .map(|rows| rows.iter().any(|row| row.jti == jti && row.uid == uid))
+                .unwrap_or(false);
+
+            if !inserted {
+                return Err(anyhow!("failed to confirm the stored token"));
+            }
+        } else {
+            return Err(anyhow!("Database response failed"));
         }
 
         self.jwt.create_jwt(&claims).map_err(Into::into)
@@ -94,13 +109,15 @@ impl TokenHandler<UserClaims> for UserTokenHandler {
             .decode_jwt::<UserClaims>(token)
             .map_err(|e| (e.to_string(), 401))?;
 
-        let user_id_hashmap = {
+        let uid_i64 = decoded_claims
+            .user_id
+            .parse::<i64>()
+            .map_err(|e| (e.to_string(), 500))?;
+
+        let jti_hashmap = {
             let mut hashmap = HashMap::new();
-            let uid_i64 = decoded_claims
-                .user_id
-                .parse::<i64>()
-                .map_err(|e| (e.to_string(), 500))?;
-            hashmap.insert(to_string_!("uid"), serde_json::json!(uid_i64));
+            // reads are limited, so ask for the token itself instead of every token of the user
+            hashmap.insert(to_string_!("jti"), serde_json::json!(decoded_claims.jti));
             hashmap
         };
 
@@ -108,7 +125,7 @@ impl TokenHandler<UserClaims> for UserTokenHandler {
             table: to_string_!("auth_tokens"),
             action: DatabaseAction::Retrieve,
             filters: Some(Filters {
-                where_clause: Some(WhereClause::Single(user_id_hashmap)),
+                where_clause: Some(WhereClause::Single(jti_hashmap)),
                 ..Default::default()
             }),
             ..Default::default()
@@ -132,13 +149,20 @@ impl TokenHandler<UserClaims> for UserTokenHandler {
                 .map_err(|e| (e.to_string(), 500))?;
 
             if db_response.is_error() {
-                return Err((format!("{}", db_response.error_message().unwrap()), 500));
+                return Err((
+                    format!("{}", db_response.error_message().unwrap()),
+                    db_response.error_code().unwrap_or_default().status_code(),
+                ));
             }
 
             match db_response {
-                DatabaseResponse::Data(token_props) => {
+                DatabaseResponse::Data(token_props)
+                | DatabaseResponse::Page {
+                    data: token_props, ..
+                } => {
                     for token_prop in token_props {
-                        if token_prop.jti == decoded_claims.jti {
+                        // the token has to belong to the user of the claims as well
+                        if token_prop.jti == decoded_claims.jti && token_prop.uid == uid_i64 {
                             return Ok(decoded_claims);
                         }
                     }
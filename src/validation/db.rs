@@ -1,5 +1,8 @@
 use crate::{
-    models::db::{DatabaseAction, DatabaseRequest, DatabaseResponse, DeleteAction, OrderDirection},
+    models::db::{
+        DatabaseAction, DatabaseErrorCode, DatabaseReply, DatabaseRequest, DatabaseResponse,
+        DeleteAction, OrderDirection, PageInfo,
+    },
     to_string_,
 };
 use anyhow::Result;
@@ -15,12 +18,23 @@ impl FromStr for DatabaseAction {
             "insert" => Ok(DatabaseAction::Insert),
             "delete_value" => Ok(DatabaseAction::Delete(DeleteAction::DeleteValue)),
             "delete_table" => Ok(DatabaseAction::Delete(DeleteAction::DeleteTable)),
+            "purge" => Ok(DatabaseAction::Delete(DeleteAction::Purge)),
             "update" => Ok(DatabaseAction::Update),
             _ => Err(format!("invalid database_action type: {}", s)),
         }
     }
 }
 
+impl DatabaseAction {
+    // actions that only read and therefore support ORDER BY, LIMIT and OFFSET
+    pub fn is_read(&self) -> bool {
+        matches!(
+            self,
+            DatabaseAction::Retrieve | DatabaseAction::Aggregate(_)
+        )
+    }
+}
+
 impl FromStr for OrderDirection {
     type Err = String;
 
@@ -50,7 +64,7 @@ impl DatabaseRequest {
         // conver to lowercase chars
         self.table = self.table.to_ascii_lowercase();
 
-        match &self.action {
+        match &mut self.action {
             DatabaseAction::BulkInsert => {
                 if self.bulk_values.is_none() {
                     return Err(to_string_!(
@@ -65,11 +79,122 @@ impl DatabaseRequest {
             }
             DatabaseAction::Delete(_action) => {}
             DatabaseAction::Update => {
-                if self.values.is_none() || self.values.as_ref().unwrap().is_empty() {
-                    return Err(to_string_!("Update action requires non-empty values."));
+                let has_values = self.values.as_ref().is_some_and(|v| !v.is_empty());
+                let has_json_updates = self.json_updates.as_ref().is_some_and(|u| !u.is_empty());
+                if !has_values && !has_json_updates {
+                    return Err(to_string_!(
+                        "Update action requires non-empty values or json_updates."
+                    ));
                 }
             }
             DatabaseAction::Retrieve => {}
+            DatabaseAction::Upsert(on_conflict) => {
+                if self.values.is_none() || self.values.as_ref().unwrap().is_empty() {
+                    return Err(to_string_!("Upsert action requires non-empty values."));
+                }
+                if on_conflict.target.is_empty() {
+                    return Err(to_string_!(
+                        "Upsert action requires at least one conflict target column."
+                    ));
+                }
+                if self.filters.is_some() {
+                    return Err(to_string_!("Upsert action does not support filters."));
+                }
+            }
+            DatabaseAction::Transaction(requests) => {
+                if requests.is_empty() {
+                    return Err(to_string_!(
+                        "Transaction action requires at least one request."
+                    ));
+                }
+                for (i, request) in requests.iter_mut().enumerate() {
+                    if let DatabaseAction::Transaction(_) = request.action {
+                        return Err(to_string_!("Transactions cannot be nested."));
+                    }
+                    request
+                        .validate()
+                        .map_err(|e| format!("request {} of the transaction: {}", i, e))?;
+                }
+            }
+            DatabaseAction::Aggregate(aggregate) => {
+                if aggregate.aggregates.is_empty() {
+                    return Err(to_string_!(
+                        "Aggregate action requires at least one aggregate function."
+                    ));
+                }
+            }
+        }
+
+        if let Some(columns) = &self.columns {
+            if self.action != DatabaseAction::Retrieve {
+                return Err(to_string_!(
+                    "columns are only supported by the Retrieve action."
+                ));
+            }
+            if columns.is_empty() {
+                return Err(to_string_!("columns cannot be empty."));
+            }
+        }
+
+        if let Some(joins) = self.joins.as_mut() {
+            if self.action != DatabaseAction::Retrieve {
+                return Err(to_string_!(
+                    "joins are only supported by the Retrieve action."
+                ));
+            }
+            if joins.is_empty() {
+                return Err(to_string_!("joins cannot be empty."));
+            }
+            for join in joins {
+                if !join
+                    .table
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_')
+                {
+                    return Err(to_string_!("Joined table name contains invalid characters. Only alphanumeric characters and underscores are allowed."));
+                }
+                join.table = join.table.to_ascii_lowercase();
+            }
+        }
+
+        if self.json_updates.is_some() && self.action != DatabaseAction::Update {
+            return Err(to_string_!(
+                "json_updates are only supported by the Update action."
+            ));
+        }
+
+        if self.atomic && self.action != DatabaseAction::BulkInsert {
+            return Err(to_string_!(
+                "atomic is only supported by the BulkInsert action."
+            ));
+        }
+
+        if self.include_deleted
+            && !matches!(
+                self.action,
+                DatabaseAction::Retrieve | DatabaseAction::Aggregate(_) | DatabaseAction::Update
+            )
+        {
+            return Err(to_string_!(
+                "include_deleted is only supported by the Retrieve, Aggregate and Update actions."
+            ));
+        }
+
+        if self.count_total && !self.action.is_read() {
+            return Err(to_string_!(
+                "count_total is only supported by reading actions."
+            ));
+        }
+
+        if let Some(returning) = &self.returning {
+            if self.action.is_read() {
+                return Err(to_string_!(
+                    "returning is not supported by reading actions, use columns instead."
+                ));
+            }
+            if returning.is_empty() {
+                return Err(to_string_!("returning cannot be empty."));
+            }
         }
 
         Ok(())
@@ -80,9 +205,9 @@ impl<T> DatabaseResponse<T>
 where
     T: for<'de> Deserialize<'de>,
 {
+    // a reply with a request_id parses as well, the id is dropped then
     pub fn parse(response: &str) -> Result<Self, String> {
-        from_str::<DatabaseResponse<T>>(response)
-            .map_err(|e| format!("failed to parse response: {}", e))
+        DatabaseReply::<T>::parse(response).map(|reply| reply.response)
     }
 
     pub fn is_error(&self) -> bool {
@@ -91,15 +216,45 @@ where
 
     pub fn error_message(&self) -> Option<&str> {
         match self {
-            DatabaseResponse::Error { error } => Some(error),
+            DatabaseResponse::Error { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+
+    pub fn error_code(&self) -> Option<DatabaseErrorCode> {
+        match self {
+            DatabaseResponse::Error { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+
+    pub fn rows_affected(&self) -> Option<u64> {
+        match self {
+            DatabaseResponse::Status { rows_affected, .. } => *rows_affected,
+            _ => None,
+        }
+    }
+
+    pub fn page_info(&self) -> Option<&PageInfo> {
+        match self {
+            DatabaseResponse::Page { page, .. } => Some(page),
             _ => None,
         }
     }
 
     pub fn get_data(self) -> Option<Vec<T>> {
         match self {
-            DatabaseResponse::Data(data) => Some(data),
+            DatabaseResponse::Data(data) | DatabaseResponse::Page { data, .. } => Some(data),
             _ => None,
         }
     }
 }
+
+impl<T> DatabaseReply<T>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    pub fn parse(reply: &str) -> Result<Self, String> {
+        from_str::<DatabaseReply<T>>(reply).map_err(|e| format!("failed to parse response: {}", e))
+    }
+}
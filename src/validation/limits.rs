@@ -0,0 +1,16 @@
+use crate::models::db::{QueryLimits, TableLimits};
+
+impl Default for TableLimits {
+    fn default() -> Self {
+        Self {
+            default_limit: 100,
+            max_limit: 1000,
+        }
+    }
+}
+
+impl QueryLimits {
+    pub fn table_limits(&self, table: &str) -> TableLimits {
+        *self.tables.get(table).unwrap_or(&self.default)
+    }
+}
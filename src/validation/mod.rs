@@ -1,2 +1,4 @@
 pub mod auth;
 pub mod db;
+pub mod limits;
+pub mod policy;
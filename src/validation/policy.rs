@@ -0,0 +1,101 @@
+use crate::models::db::{
+    DatabaseAction, DeleteAction, PolicyAction, QueryBuilder, QueryPolicy, TablePolicy,
+};
+use std::collections::HashSet;
+
+impl PolicyAction {
+    // taken from the query builder so the action is the one its sql actually does,
+    // e.g. a delete without a where clause on behalf of an owner only deletes their rows
+    pub fn from_query_builder(query_builder: &QueryBuilder) -> Option<Self> {
+        match &query_builder.action {
+            DatabaseAction::Retrieve => Some(PolicyAction::Retrieve),
+            DatabaseAction::Aggregate(_) => Some(PolicyAction::Aggregate),
+            DatabaseAction::Insert => Some(PolicyAction::Insert),
+            DatabaseAction::BulkInsert => Some(PolicyAction::BulkInsert),
+            DatabaseAction::Upsert(_) => Some(PolicyAction::Upsert),
+            DatabaseAction::Update => Some(PolicyAction::Update),
+            DatabaseAction::Delete(DeleteAction::DeleteTable) => Some(PolicyAction::DropTable),
+            DatabaseAction::Delete(action) => {
+                if query_builder.truncates(action) {
+                    Some(PolicyAction::Truncate)
+                } else if *action == DeleteAction::Purge {
+                    Some(PolicyAction::Purge)
+                } else {
+                    // soft deletes included, they only mark the rows as deleted
+                    Some(PolicyAction::DeleteValue)
+                }
+            }
+            DatabaseAction::Transaction(_) => None, // checked request by request
+        }
+    }
+
+    pub fn is_destructive(&self) -> bool {
+        matches!(self, PolicyAction::Truncate | PolicyAction::DropTable)
+    }
+}
+
+impl TablePolicy {
+    pub fn allow_all() -> Self {
+        Self {
+            allowed: HashSet::from([
+                PolicyAction::Retrieve,
+                PolicyAction::Aggregate,
+                PolicyAction::Insert,
+                PolicyAction::BulkInsert,
+                PolicyAction::Upsert,
+                PolicyAction::Update,
+                PolicyAction::DeleteValue,
+                PolicyAction::Purge,
+                PolicyAction::Truncate,
+                PolicyAction::DropTable,
+            ]),
+        }
+    }
+
+    pub fn read_only() -> Self {
+        Self {
+            allowed: HashSet::from([PolicyAction::Retrieve, PolicyAction::Aggregate]),
+        }
+    }
+}
+
+impl Default for TablePolicy {
+    // everything but purging, emptying or dropping the table,
+    // so soft deletes stay reversible unless purging is allowed explicitly
+    fn default() -> Self {
+        let mut policy = Self::allow_all();
+        policy.allowed.remove(&PolicyAction::Purge);
+        policy.allowed.remove(&PolicyAction::Truncate);
+        policy.allowed.remove(&PolicyAction::DropTable);
+        policy
+    }
+}
+
+impl QueryPolicy {
+    pub fn table_policy(&self, table: &str) -> &TablePolicy {
+        self.tables.get(table).unwrap_or(&self.default)
+    }
+
+    // the request has to be validated first so the table name is normalized,
+    // and the query builder needs its model registry and owner to tell what the sql does
+    pub fn check(&self, query_builder: &QueryBuilder) -> Result<(), String> {
+        let action = match PolicyAction::from_query_builder(query_builder) {
+            Some(action) => action,
+            None => return Ok(()),
+        };
+
+        let table = &query_builder.table;
+        if !self.table_policy(table).allowed.contains(&action) {
+            return Err(format!("{:?} is not allowed on table {}", action, table));
+        }
+
+        if action.is_destructive() && !query_builder.confirm_destructive {
+            return Err(format!(
+                "{:?} on table {} requires confirm_destructive",
+                action, table
+            ));
+        }
+
+        Ok(())
+    }
+}